@@ -17,18 +17,21 @@
 
 use log::*;
 use simplelog::SimpleLogger;
-use noisefunge::jack::*;
+use noisefunge::backend::{self, ClockMsg};
+use noisefunge::clock::{ClockHealth, Tempo, TapTempo};
 use noisefunge::server::*;
 use noisefunge::server::FungeRequest::*;
 use noisefunge::befunge::*;
 use noisefunge::config::*;
 use noisefunge::api::*;
 use noisefunge::midi_bridge::*;
+use noisefunge::osc::OscOutput;
 use noisefunge::subprocess::*;
 use std::fs;
+use std::collections::{HashMap, VecDeque};
 use std::sync::{Arc};
+use std::time::Duration;
 use crossbeam_channel::select;
-use serde_json::{to_vec};
 
 use clap::{Arg, App};
 
@@ -45,16 +48,34 @@ struct FungedServer {
     config: FungedConfig,
     engine: Engine,
     state: EngineState,
-    state_vec: Arc<Vec<u8>>,
-    waiting: Vec<(u64, Responder<Option<Arc<Vec<u8>>>>)>
+    history: VecDeque<EngineState>,
+    encoded: HashMap<(Option<u64>, bool, Encoding), Arc<Vec<u8>>>,
+    events: EventBroadcast,
+    waiting: Vec<(Option<u64>, bool, Encoding,
+                  Responder<Option<Arc<Vec<u8>>>>)>,
+    tempo: Option<Tempo>,
+    taps: TapTempo,
+    running: bool,
+    song_tick: u64, // Clock ticks since the start of the song
+    health: Option<ClockHealth>,
+    missed: u64
 }
 
+// Number of previous states kept around to compute deltas against.
+const STATE_HISTORY: usize = 32;
+
+// How often the clock is checked on when no ticks are coming in.
+const HEALTH_INTERVAL: Duration = Duration::from_millis(250);
+
 impl FungedServer {
 
     fn new(conf: FungedConfig) -> Self {
         let mut engine = Engine::new(conf.period);
+        engine.set_charmap(conf.charmap.clone());
+        for ch in &conf.outbound {
+            engine.add_outbound(*ch);
+        }
         let state = engine.state();
-        let state_vec = Arc::new(to_vec(&state).unwrap());
 
         for filename in &conf.preload {
             let prog = fs::read_to_string(filename).expect(
@@ -72,45 +93,194 @@ impl FungedServer {
             config: conf,
             engine: engine,
             state: state,
-            state_vec: state_vec,
-            waiting: Vec::new()
+            history: VecDeque::new(),
+            encoded: HashMap::new(),
+            events: EventBroadcast::new(),
+            waiting: Vec::new(),
+            tempo: None,
+            taps: TapTempo::new(),
+            running: true,
+            song_tick: 0,
+            health: None,
+            missed: 0
         }
     }
 
     fn handle(&mut self, request: FungeRequest) {
         match request {
-            StartProcess(name, prog, rspndr) =>
-                rspndr.respond(match Prog::parse(&prog) {
-                    Ok(p) => Ok(self.engine.make_process(name, p)),
-                    Err(e) => Err(e.to_string())
-                }),
-            GetState(prev, rspndr) => {
-                let prev = prev.unwrap_or(0);
-                if prev < self.state.beat {
-                    rspndr.respond(Some(Arc::clone(&self.state_vec)));
-                } else if prev > self.state.beat {
+            StartProcess(req, rspndr) => {
+                let cm = self.engine.charmap();
+                let parsed = if req.strict {
+                    Prog::parse_strict(&req.program, req.mode, cm,
+                                       self.engine.ops())
+                } else {
+                    Prog::parse_mode(&req.program, req.mode, cm)
+                };
+                rspndr.respond(parsed.map(|p|
+                    self.engine.make_process(req.name, p)))
+            },
+            GetState(prev, delta, enc, rspndr) => {
+                let beat = prev.unwrap_or(0);
+                if beat < self.state.beat {
+                    let vec = self.encode_state(prev, delta, enc);
+                    rspndr.respond(Some(vec));
+                } else if beat > self.state.beat {
                     rspndr.respond(None);
                 } else {
-                    self.waiting.push((prev, rspndr));
+                    self.waiting.push((prev, delta, enc, rspndr));
                 }
             },
+            Subscribe(since, enc, rspndr) =>
+                rspndr.respond(self.events.subscribe(since, enc)),
+            GetProcess(pid, rspndr) =>
+                rspndr.respond(self.engine.process_detail(pid)),
+            GetInfo(rspndr) => rspndr.respond(self.info()),
             Kill(killreq) => { self.engine.kill(killreq) },
+            Send(ch, values, rspndr) => {
+                let sent = self.engine.send(ch, &values);
+                if !sent {
+                    warn!("Channel {} is full, dropped {} values", ch,
+                          values.len());
+                }
+                rspndr.respond(sent)
+            },
+            Receive(ch, rspndr) =>
+                rspndr.respond(self.engine.read_outbound(ch)),
+            SetTempo(bpm, rspndr) => {
+                if let Some(tempo) = &self.tempo {
+                    info!("Tempo set to {} BPM", bpm);
+                    tempo.set(bpm);
+                }
+                rspndr.respond(self.tempo.as_ref().map(|t| t.get()))
+            },
+            SetRunning(running, rspndr) => {
+                if let Some(tempo) = &self.tempo {
+                    info!("Internal clock {}",
+                          if running { "running" } else { "stopped" });
+                    tempo.set_running(running);
+                }
+                rspndr.respond(self.tempo.as_ref().map(|t| t.running()))
+            },
+            Tap(now, rspndr) => {
+                if let Some(tempo) = &self.tempo {
+                    if let Some(bpm) = self.taps.tap(now) {
+                        debug!("Tapped tempo: {} BPM", bpm);
+                        tempo.set(bpm);
+                    }
+                }
+                rspndr.respond(self.tempo.as_ref().map(|t| t.get()))
+            },
+        };
+    }
+
+    // Serialize the current state for a client that last saw prev. Clients
+    // asking for a delta get a StateUpdate, which is a delta if prev is
+    // still in the history; everyone else gets a plain EngineState.
+    fn encode_state(&mut self, prev: Option<u64>, delta: bool,
+                    enc: Encoding) -> Arc<Vec<u8>> {
+        if let Some(vec) = self.encoded.get(&(prev, delta, enc)) {
+            return Arc::clone(vec);
+        }
+        let vec = match prev {
+            Some(beat) if delta => {
+                let update = match self.history.iter()
+                                               .find(|s| s.beat == beat) {
+                    Some(old) => StateUpdate::Delta(self.state.delta(old)),
+                    None => StateUpdate::Full(self.state.clone()),
+                };
+                enc.encode(&update)
+            },
+            _ => enc.encode(&self.state),
         };
+        let vec = Arc::new(vec);
+        self.encoded.insert((prev, delta, enc), Arc::clone(&vec));
+        vec
+    }
+
+    fn info(&self) -> ServerInfo {
+        ServerInfo { version: env!("CARGO_PKG_VERSION").to_string(),
+                     period: self.config.period,
+                     ops: self.engine.ops_info(),
+                     charmap: self.engine.charmap().as_string(),
+                     channels: self.config.channel_info(),
+                     outbound: self.config.outbound.clone() }
+    }
+
+    fn clock_state(&self) -> ClockState {
+        let health = self.health.as_ref();
+        ClockState { internal: self.tempo.is_some(),
+                     tempo: self.tempo.as_ref().map(|t| t.get()),
+                     running: self.running,
+                     bpm: health.and_then(|h| h.bpm()),
+                     jitter: health.and_then(|h| h.jitter()),
+                     missed: self.missed,
+                     stalled: health.map(|h| h.stalled()).unwrap_or(false),
+                     failover: health.map(|h| h.failover()).unwrap_or(false) }
+    }
+
+    // Jump to a tick within the song. The engine picks up from the next
+    // whole beat.
+    fn locate(&mut self, tick: u64) {
+        let period = self.config.period;
+        self.song_tick = tick;
+        self.engine.set_position((tick + period - 1) / period);
     }
 
     fn update_state(&mut self) {
-        self.state = self.engine.state();
-        self.state_vec = Arc::new(to_vec(&self.state).unwrap());
-        let state_vec = &self.state_vec;
+        let clock = self.clock_state();
+        if clock.failover != self.state.clock.failover {
+            if clock.failover {
+                warn!("Lost the external clock, falling back to {:.1} BPM",
+                      clock.bpm.unwrap_or(0.0));
+            } else {
+                info!("External clock is back, switching to it");
+            }
+        }
+        if clock.stalled != self.state.clock.stalled {
+            if clock.stalled {
+                warn!("Clock stalled");
+            } else {
+                info!("Clock recovered");
+            }
+        }
+        // The clock and song position can change while the engine is
+        // stopped, so they're updated in place, and clients that saw this
+        // beat are told again.
+        if self.engine.beat() == self.state.beat {
+            let position = self.engine.position();
+            if clock != self.state.clock || position != self.state.position {
+                self.state.clock = clock;
+                self.state.position = position;
+                self.encoded.clear();
+                self.answer_waiting(true);
+            }
+            return;
+        }
+        let mut state = self.engine.state();
+        state.clock = clock;
+        let old = std::mem::replace(&mut self.state, state);
+        self.history.push_back(old);
+        if self.history.len() > STATE_HISTORY {
+            self.history.pop_front();
+        }
+        self.encoded.clear();
+        self.answer_waiting(false);
+    }
+
+    // Answer the clients waiting for a newer state, including those that
+    // saw the current beat if it's since changed in place.
+    fn answer_waiting(&mut self, changed: bool) {
         let beat = self.state.beat;
-        self.waiting.retain(|(prev, rspndr)|
-            if *prev < beat {
-                rspndr.respond(Some(Arc::clone(state_vec)));
-                false
+        let waiting = std::mem::take(&mut self.waiting);
+        for (prev, delta, enc, rspndr) in waiting {
+            let seen = prev.unwrap_or(0);
+            if seen < beat || (changed && seen == beat) {
+                let vec = self.encode_state(prev, delta, enc);
+                rspndr.respond(Some(vec));
             } else {
-                true
+                self.waiting.push((prev, delta, enc, rspndr));
             }
-        );
+        }
     }
 }
 
@@ -123,38 +293,71 @@ fn main() {
 
     let mut subs = SubprocessHandle::new(server.config.subprocesses.clone());
 
-    let mut handle = JackHandle::new(&server.config);
-    let mut connect_handle = handle.take_connect_handle();
-    let mut prev_missed = 0;
-    let mut bridge = MidiBridge::new(&server.config, &handle);
+    let backend = backend::open(&server.config);
+    server.tempo = backend.tempo();
+    server.health = backend.health();
+    let mut bridge = MidiBridge::new(&server.config, &backend);
+    let osc_out = OscOutput::new(&server.config);
     let http_serv = ServerHandle::new(&server.config);
     let mut prev_i = 0;
 
     loop {
         let mut attempt_cleanup = false;
         select! {
-            recv(handle.beat_channel) -> msg => {
-                let i = msg.expect("Failed to read from beat channel.");
-                for j in prev_i..i {
-                    if j % server.config.period == 0 {
-                        let (beat, log) = server.engine.step();
-                        bridge.step(beat, &log);
-                    }
-                    if i % 100 == 0 {
-                        attempt_cleanup = true;
-                    }
-                };
-                server.update_state();
-                prev_i = i;
-                let missed = handle.missed();
-                if missed != prev_missed {
-                    error!("Missed {} beats", missed - prev_missed); 
-                    prev_missed = missed;
+            recv(backend.beats()) -> msg => {
+                match msg.expect("Failed to read from beat channel.") {
+                    ClockMsg::Tick(i) => {
+                        for j in prev_i..i {
+                            if !server.running { continue }
+                            if server.song_tick % server.config.period == 0 {
+                                let (beat, log) = server.engine.step();
+                                // Tick j + 1 set this step off, so its
+                                // notes go out a tick later.
+                                bridge.due_at((j + 2) as f64);
+                                bridge.step(beat, &log);
+                                osc_out.step(beat, &log, &server.engine);
+                                server.events.push(beat, &log);
+                            }
+                            server.song_tick += 1;
+                        }
+                        if i > prev_i && i % 100 == 0 {
+                            attempt_cleanup = true;
+                        }
+                        prev_i = i;
+                    },
+                    ClockMsg::Start => {
+                        info!("Clock started");
+                        server.locate(0);
+                        server.running = true;
+                    },
+                    ClockMsg::Continue => {
+                        info!("Clock continued");
+                        server.running = true;
+                    },
+                    ClockMsg::Stop => {
+                        info!("Clock stopped");
+                        server.running = false;
+                        bridge.silence(server.engine.beat());
+                    },
+                    ClockMsg::Position(pos) => {
+                        debug!("Song position: {}", pos);
+                        server.locate(pos * 6);
+                    },
+                    ClockMsg::Locate(tick) => {
+                        debug!("Located to tick {}", tick);
+                        server.locate(tick);
+                    },
+                }
+                let missed = backend.missed();
+                if missed != server.missed {
+                    error!("Missed {} beats", missed - server.missed);
+                    server.missed = missed;
                 }
+                server.update_state();
             },
-            recv(handle.err_channel) -> msg => {
+            recv(backend.errors()) -> msg => {
                 let msg = msg.expect("Failed to read from error channel.");
-                error!("Error from jack thread: {:?}", msg);
+                error!("Error from MIDI backend: {}", msg);
             }
             recv(http_serv.channel) -> msg => {
                 match msg {
@@ -162,10 +365,88 @@ fn main() {
                     Err(e) => panic!("Server error: {:?}", e),
                 };
             }
+            default(HEALTH_INTERVAL) => server.update_state(),
         }
         if attempt_cleanup {
-            connect_handle.join();
+            backend.maintain();
             subs.check_children();
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use noisefunge::clock::ClockMeter;
+
+    fn test_server(name: &str) -> FungedServer {
+        let path = std::env::temp_dir().join(format!("{}.toml", name));
+        fs::write(&path, "backend = \"null\"\n").unwrap();
+        FungedServer::new(FungedConfig::read_offline(path.to_str().unwrap()))
+    }
+
+    // Ask for the state after beat, as a long-polling viewer would.
+    fn poll(server: &mut FungedServer, beat: u64)
+        -> Responder<Option<Arc<Vec<u8>>>> {
+        let rspndr = Responder::new();
+        server.handle(GetState(Some(beat), false, Encoding::Json,
+                               rspndr.clone()));
+        rspndr
+    }
+
+    fn answer(rspndr: Responder<Option<Arc<Vec<u8>>>>) -> EngineState {
+        let vec = rspndr.wait().unwrap().unwrap();
+        serde_json::from_slice(&vec).unwrap()
+    }
+
+    #[test]
+    fn stall_reaches_waiting() {
+        let mut server = test_server("funged_stall");
+        let health = ClockHealth::new();
+        server.health = Some(health.clone());
+        server.update_state();
+        let beat = server.state.beat;
+
+        let rspndr = poll(&mut server, beat);
+        server.update_state();
+        assert_eq!(server.waiting.len(), 1);
+
+        // A quarter note of ticks goes missing, and the beat never moves.
+        let mut meter = ClockMeter::new();
+        for i in 0..4 {
+            meter.tick(i * 960);
+        }
+        health.publish(&meter, 48000, 30 * 960, false);
+        server.update_state();
+        assert!(server.waiting.is_empty());
+        let state = answer(rspndr);
+        assert_eq!(state.beat, beat);
+        assert!(state.clock.stalled);
+    }
+
+    #[test]
+    fn transport_reaches_waiting() {
+        let mut server = test_server("funged_transport");
+        server.update_state();
+        let beat = server.state.beat;
+
+        // Stopping freezes the beat.
+        let rspndr = poll(&mut server, beat);
+        server.running = false;
+        server.update_state();
+        let state = answer(rspndr);
+        assert_eq!(state.beat, beat);
+        assert!(!state.clock.running);
+
+        // So does moving the song position while stopped.
+        let rspndr = poll(&mut server, beat);
+        server.locate(96);
+        server.update_state();
+        assert_eq!(answer(rspndr).position, 4);
+
+        let rspndr = poll(&mut server, beat);
+        server.running = true;
+        server.update_state();
+        assert!(answer(rspndr).clock.running);
+    }
+}
@@ -0,0 +1,357 @@
+/*
+    Noisefunge Copyright (C) 2021 Rev. Johnny Healey <rev.null@gmail.com>
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// ALSA sequencer backend. Ports are named the same way as in the jack
+// config, except that remote ports are "client:port", where either half may
+// be a name or a number.
+
+use alsa::Direction;
+use alsa::seq::{Addr, ClientIter, EvCtrl, EvNote, Event, EventType, PortCap,
+                PortIter, PortSubscribe, PortType, Seq};
+use arr_macro::arr;
+use log::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use crossbeam_channel::{bounded, Sender, Receiver};
+
+use crate::backend::{Backend, BackendError, ClockMsg};
+use crate::clock::{spawn_clock, ClockHealth, ClockSender, Tempo};
+use crate::config::{ClockSource, FungedConfig};
+use crate::jack::MidiMsg;
+use crate::midi_bridge::MidiSink;
+
+// Failed reads back off, doubling from 10ms up to this many doublings.
+// Reaching the cap is reported as an error.
+const READ_BACKOFF: u32 = 7;
+
+pub struct AlsaHandle {
+    seq: Seq,
+    mapping: [Option<(u8, i32)>; 256], // MIDI channel and port
+    beat_channel: Receiver<ClockMsg>,
+    err_channel: Receiver<BackendError>,
+    err_sender: Sender<BackendError>,
+    missed_beats: Arc<AtomicU64>,
+    pending: RefCell<Vec<(String, String)>>,
+    instrs: Vec<MidiMsg>,
+    tempo: Option<Tempo>,
+}
+
+fn cstr(s: &str) -> CString {
+    CString::new(s).expect("Port names can't contain NUL")
+}
+
+// Look up a remote port by number, or by client and port name.
+fn resolve(seq: &Seq, name: &str) -> Option<Addr> {
+    if let Ok(addr) = Addr::from_str(name) {
+        return Some(addr);
+    }
+    let mut split = name.rsplitn(2, ':');
+    let port = split.next()?;
+    let client = split.next()?;
+    for ci in ClientIter::new(seq) {
+        if ci.get_name().ok() != Some(client)
+           && client.parse() != Ok(ci.get_client()) {
+            continue;
+        }
+        for pi in PortIter::new(seq, ci.get_client()) {
+            if pi.get_name().ok() == Some(port)
+               || port.parse() == Ok(pi.get_port()) {
+                return Some(pi.addr());
+            }
+        }
+    }
+    None
+}
+
+impl AlsaHandle {
+    pub fn new(conf: &FungedConfig) -> AlsaHandle {
+        let seq = Seq::open(None, Some(Direction::Playback), false)
+                      .expect("Failed to open ALSA sequencer.");
+        seq.set_client_name(&cstr("noisefunge")).unwrap();
+
+        let mut ports = HashMap::new();
+        for name in &conf.locals {
+            let port = seq.create_simple_port(
+                               &cstr(name),
+                               PortCap::READ | PortCap::SUBS_READ,
+                               PortType::MIDI_GENERIC | PortType::APPLICATION)
+                          .expect("Failed to register port");
+            ports.insert(Rc::clone(name), port);
+        }
+
+        let mut mapping = arr![None; 256];
+        let mut instrs = Vec::new();
+        for i in 0..=255 {
+            let cc = match &conf.channels[i] {
+                Some(cc) => cc,
+                None => continue
+            };
+            mapping[i] = Some((cc.midi_channel(i as u8), ports[&cc.local]));
+            if cc.bank.is_some() || cc.program.is_some() {
+                instrs.push(MidiMsg::Program(i as u8, cc.bank, cc.program));
+            };
+            if let Some(pan) = cc.pan {
+                instrs.push(MidiMsg::Pan(i as u8, pan));
+            }
+        }
+
+        let (beat_snd, beat_rcv) = bounded(16);
+        let (err_snd, err_rcv) = bounded(128);
+        let missed = Arc::new(AtomicU64::new(0));
+        let mut pending = Vec::new();
+        let client = seq.client_id().unwrap();
+        if conf.clock_out.is_some() {
+            warn!("clock_out is only supported by the jack backend");
+        }
+        for (src, dst) in &conf.connections {
+            if let Some(port) = ports.get(src) {
+                pending.push((format!("{}:{}", client, port), dst.clone()));
+            }
+        }
+        pending.extend(conf.extra_connections.clone());
+
+        let tempo = match conf.clock {
+            ClockSource::Internal => {
+                let tempo = Tempo::new(conf.tempo.unwrap_or(120.0));
+                spawn_clock(tempo.clone(), beat_snd, missed.clone());
+                Some(tempo)
+            },
+            ClockSource::External => {
+                let beats_in = AlsaHandle::start_clock(beat_snd,
+                                                       err_snd.clone(),
+                                                       missed.clone());
+                let beat_source = conf.beat_source.as_ref()
+                                      .expect("The alsa backend needs \
+                                               beats_in");
+                pending.push((beat_source.to_string(),
+                              format!("{}:{}", beats_in.client,
+                                      beats_in.port)));
+                None
+            },
+            ClockSource::Transport =>
+                panic!("Only the jack backend can follow the transport"),
+        };
+
+        let handle = AlsaHandle { seq: seq,
+                                  mapping: mapping,
+                                  beat_channel: beat_rcv,
+                                  err_channel: err_rcv,
+                                  err_sender: err_snd,
+                                  missed_beats: missed,
+                                  pending: RefCell::new(pending),
+                                  instrs: instrs,
+                                  tempo: tempo };
+        handle.maintain();
+        handle
+    }
+
+    // Pass on clock and transport messages arriving at our own client's
+    // beats_in port.
+    fn start_clock(beat_snd: Sender<ClockMsg>, err_snd: Sender<BackendError>,
+                   missed: Arc<AtomicU64>) -> Addr {
+        let seq = Seq::open(None, Some(Direction::Capture), false)
+                      .expect("Failed to open ALSA sequencer.");
+        seq.set_client_name(&cstr("noisefunge clock")).unwrap();
+        let port = seq.create_simple_port(
+                           &cstr("beats_in"),
+                           PortCap::WRITE | PortCap::SUBS_WRITE,
+                           PortType::MIDI_GENERIC | PortType::APPLICATION)
+                      .expect("Failed to register port");
+        let addr = Addr { client: seq.client_id().unwrap(), port: port };
+
+        thread::spawn(move || {
+            let mut input = seq.input();
+            let mut out = ClockSender::new(beat_snd, missed);
+            let mut tick = 0;
+            let mut failures = 0;
+            loop {
+                let ev = match input.event_input() {
+                    Ok(ev) => {
+                        failures = 0;
+                        ev
+                    },
+                    Err(e) => {
+                        warn!("Failed to read from sequencer: {}", e);
+                        let wait = 10 << failures.min(READ_BACKOFF);
+                        if failures < READ_BACKOFF {
+                            failures += 1;
+                        } else if failures == READ_BACKOFF {
+                            failures += 1;
+                            let _ = err_snd.try_send(BackendError::ReadFailed);
+                        }
+                        thread::sleep(Duration::from_millis(wait));
+                        continue;
+                    }
+                };
+                let msg = match ev.get_type() {
+                    EventType::Clock => {
+                        tick += 1;
+                        ClockMsg::Tick(tick)
+                    },
+                    EventType::Start => ClockMsg::Start,
+                    EventType::Continue => ClockMsg::Continue,
+                    EventType::Stop => ClockMsg::Stop,
+                    EventType::Songpos => {
+                        match ev.get_data::<EvCtrl>() {
+                            Some(ctrl) => ClockMsg::Position(ctrl.value as u64),
+                            None => continue
+                        }
+                    },
+                    _ => continue
+                };
+                if !out.send_blocking(msg) { break }
+            }
+        });
+        addr
+    }
+
+    fn output(&self, port: i32, mut ev: Event) -> bool {
+        ev.set_source(port);
+        ev.set_subs();
+        ev.set_direct();
+        if self.seq.event_output_direct(&mut ev).is_err() {
+            let _ = self.err_sender.try_send(BackendError::WriteFailed);
+        }
+        true
+    }
+}
+
+impl MidiSink for AlsaHandle {
+    fn send_midi(&self, _beat: u64, msg: MidiMsg) -> bool {
+        let ch = match msg {
+            MidiMsg::On(ch, _, _) | MidiMsg::Off(ch, _)
+                | MidiMsg::Program(ch, _, _) | MidiMsg::Pan(ch, _)
+                | MidiMsg::AllOff(ch) => ch,
+        };
+        let (channel, port) = match self.mapping[ch as usize] {
+            Some(m) => m,
+            None => {
+                let _ = self.err_sender.try_send(
+                    BackendError::UnknownChannel(ch));
+                return true;
+            }
+        };
+        let ctrl = |param, value| EvCtrl { channel: channel,
+                                            param: param,
+                                            value: value };
+        match msg {
+            MidiMsg::On(_, pch, vel) => {
+                let note = EvNote { channel: channel, note: pch,
+                                    velocity: vel, off_velocity: 0,
+                                    duration: 0 };
+                self.output(port, Event::new(EventType::Noteon, &note))
+            },
+            MidiMsg::Off(_, pch) => {
+                let note = EvNote { channel: channel, note: pch,
+                                    velocity: 0, off_velocity: 0,
+                                    duration: 0 };
+                self.output(port, Event::new(EventType::Noteoff, &note))
+            },
+            MidiMsg::Program(_, bank, patch) => {
+                if let Some(bank) = bank {
+                    let hi = ctrl(0, (bank >> 7) as i32);
+                    let lo = ctrl(32, (bank & 127) as i32);
+                    self.output(port, Event::new(EventType::Controller, &hi));
+                    self.output(port, Event::new(EventType::Controller, &lo));
+                }
+                if let Some(patch) = patch {
+                    let pc = ctrl(0, patch as i32);
+                    self.output(port, Event::new(EventType::Pgmchange, &pc));
+                }
+                true
+            },
+            MidiMsg::Pan(_, pan) => {
+                let pan = ctrl(10, (pan & 127) as i32);
+                self.output(port, Event::new(EventType::Controller, &pan))
+            },
+            MidiMsg::AllOff(_) => {
+                let off = ctrl(123, 0);
+                self.output(port, Event::new(EventType::Controller, &off))
+            },
+        }
+    }
+}
+
+impl Backend for AlsaHandle {
+    fn beats(&self) -> &Receiver<ClockMsg> {
+        &self.beat_channel
+    }
+
+    fn errors(&self) -> &Receiver<BackendError> {
+        &self.err_channel
+    }
+
+    fn missed(&self) -> u64 {
+        self.missed_beats.load(Ordering::Relaxed)
+    }
+
+    // Retry connections to ports that didn't exist yet.
+    fn maintain(&self) {
+        let mut pending = self.pending.borrow_mut();
+        if pending.is_empty() {
+            return;
+        }
+        let mut new_conn = false;
+        pending.retain(|(src, dst)| {
+            let (sender, dest) = match (resolve(&self.seq, src),
+                                        resolve(&self.seq, dst)) {
+                (Some(s), Some(d)) => (s, d),
+                _ => {
+                    warn!("Failed to connect: {} -> {}: no such port",
+                          src, dst);
+                    return true;
+                }
+            };
+            let sub = PortSubscribe::empty().unwrap();
+            sub.set_sender(sender);
+            sub.set_dest(dest);
+            match self.seq.subscribe_port(&sub) {
+                Ok(_) => {
+                    info!("Connected: {} -> {}", src, dst);
+                    new_conn = true;
+                    false
+                },
+                Err(e) => {
+                    warn!("Failed to connect: {} -> {}: {}", src, dst, e);
+                    true
+                }
+            }
+        });
+        drop(pending);
+
+        if new_conn {
+            for m in &self.instrs {
+                self.send_midi(0, *m);
+            }
+        }
+    }
+
+    fn tempo(&self) -> Option<Tempo> {
+        self.tempo.clone()
+    }
+
+    fn health(&self) -> Option<ClockHealth> {
+        None
+    }
+}
@@ -15,26 +15,119 @@
     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-use crate::befunge::{CrashReason, Note};
+use crate::befunge::{CrashReason, Dir, EventLog, Note, ProcessState,
+                     ParseError, SourceMode};
 
 use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex, Condvar};
 use std::thread;
 use std::time::Duration;
 
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError};
+use reqwest::Url;
 use reqwest::blocking::Client;
+use reqwest::header::{ACCEPT, CONTENT_TYPE};
 use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+
+pub const MSGPACK_MIME: &str = "application/msgpack";
+
+// Wire format for state and events. JSON is the default; clients that send
+// an Accept header naming MessagePack get that instead.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Json,
+    MsgPack
+}
+
+impl Encoding {
+    pub fn from_accept(accept: Option<&str>) -> Self {
+        match accept {
+            Some(a) if a.split(',').any(|t| Encoding::is_msgpack(t)) =>
+                Encoding::MsgPack,
+            _ => Encoding::Json,
+        }
+    }
+
+    pub fn from_content_type(content_type: Option<&str>) -> Self {
+        match content_type {
+            Some(t) if Encoding::is_msgpack(t) => Encoding::MsgPack,
+            _ => Encoding::Json,
+        }
+    }
+
+    fn is_msgpack(mime: &str) -> bool {
+        let mime = mime.split(';').next().unwrap_or("").trim();
+        mime == MSGPACK_MIME || mime == "application/x-msgpack"
+    }
+
+    fn accept(&self) -> &'static str {
+        match self {
+            Encoding::Json => "application/json",
+            Encoding::MsgPack => MSGPACK_MIME,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Encoding::Json => "application/json; charset=utf-8",
+            Encoding::MsgPack => MSGPACK_MIME,
+        }
+    }
+
+    pub fn encode<T: Serialize>(&self, t: &T) -> Vec<u8> {
+        match self {
+            Encoding::Json => serde_json::to_vec(t).unwrap(),
+            Encoding::MsgPack => rmp_serde::to_vec_named(t).unwrap(),
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8])
+        -> Result<T, String> {
+        match self {
+            Encoding::Json => serde_json::from_slice(bytes)
+                .map_err(|e| format!("Serialization error: {:?}", e)),
+            Encoding::MsgPack => rmp_serde::from_slice(bytes)
+                .map_err(|e| format!("Serialization error: {:?}", e)),
+        }
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct NewProcessReq {
     pub name: Option<String>,
-    pub program: String
+    pub program: String,
+    #[serde(default)]
+    pub mode: SourceMode,
+    #[serde(default)]
+    pub strict: bool
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct NewProcessResp { pub pid: u64 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+// Body of the 400 response for a program that fails to parse.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ProgramErrorResp {
+    pub message: String,
+    pub line: Option<usize>,
+    pub col: Option<usize>
+}
+
+impl From<ParseError> for ProgramErrorResp {
+    fn from(e: ParseError) -> Self {
+        let pos = e.position();
+        ProgramErrorResp { message: e.to_string(),
+                           line: pos.map(|p| p.0),
+                           col: pos.map(|p| p.1) }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct ProcState {
     pub name: usize,
     pub call_stack: Vec<(usize, usize)>, // prog index, pc
@@ -44,28 +137,198 @@ pub struct ProcState {
     pub play: Option<Note>,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FrameState {
+    pub prog: (usize, String), // width, text
+    pub pc: usize,
+    pub dir: Dir,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ProcDetail {
+    pub pid: u64,
+    pub name: Option<String>,
+    pub spawned: u64,
+    pub state: ProcessState,
+    pub data_stack: Vec<u8>,
+    pub call_stack: Vec<FrameState>,
+    pub note: Note,
+    pub output: Option<String>,
+}
+
+// Where the beats are coming from. Tempo is only known for the internal
+// clock; bpm and jitter (in ms) are measured from the ticks, where the
+// backend can do that. Failover is set while a fallback clock stands in for
+// a missing external one.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct ClockState {
+    pub internal: bool,
+    pub tempo: Option<f64>,
+    pub running: bool,
+    #[serde(default)]
+    pub bpm: Option<f64>,
+    #[serde(default)]
+    pub jitter: Option<f64>,
+    #[serde(default)]
+    pub missed: u64,
+    #[serde(default)]
+    pub stalled: bool,
+    #[serde(default)]
+    pub failover: bool,
+}
+
+// A one line summary for the viewers.
+impl fmt::Display for ClockState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.bpm.or(self.tempo) {
+            Some(bpm) => write!(f, "{:.1} BPM", bpm)?,
+            None => write!(f, "--- BPM")?,
+        }
+        if let Some(jitter) = self.jitter {
+            write!(f, " ~{:.2}ms", jitter)?;
+        }
+        if self.missed > 0 {
+            write!(f, " missed:{}", self.missed)?;
+        }
+        if self.failover {
+            write!(f, " FALLBACK")?;
+        } else if self.stalled {
+            write!(f, " STALLED")?;
+        } else if !self.running {
+            write!(f, " stopped")?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct EngineState {
     pub beat: u64,
+    #[serde(default)]
+    pub position: u64,
     pub names: Vec<String>,
     pub progs: Vec<(usize, String)>,
     pub procs: HashMap<u64, ProcState>,
     pub sleeping: usize,
     pub buffers: BTreeMap<u8, i64>,
-    pub crashed: Vec<(u64, CrashReason)>
+    pub crashed: Vec<(u64, CrashReason)>,
+    #[serde(default)]
+    pub clock: ClockState
 }
 
 impl EngineState {
     pub fn new() -> Self {
         EngineState {
             beat: 0,
+            position: 0,
             names: Vec::new(),
             progs: Vec::new(),
             procs: HashMap::new(),
             sleeping: 0,
             buffers: BTreeMap::new(),
             crashed: Vec::new(),
+            clock: ClockState::default(),
+        }
+    }
+}
+
+// An entry in a delta is either carried over from an index in the previous
+// state, or sent in full.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum DeltaEntry<T> {
+    Same(usize),
+    New(T)
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StateDelta {
+    pub prev: u64,
+    pub beat: u64,
+    #[serde(default)]
+    pub position: u64,
+    pub names: Vec<DeltaEntry<String>>,
+    pub progs: Vec<DeltaEntry<(usize, String)>>,
+    pub changed: HashMap<u64, ProcState>,
+    pub removed: Vec<u64>,
+    pub sleeping: usize,
+    pub buffers: BTreeMap<u8, i64>,
+    pub crashed: Vec<(u64, CrashReason)>,
+    #[serde(default)]
+    pub clock: ClockState
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum StateUpdate {
+    Full(EngineState),
+    Delta(StateDelta)
+}
+
+fn delta_entries<T>(prev: &Vec<T>, next: &Vec<T>) -> Vec<DeltaEntry<T>>
+    where T: Clone + Eq + std::hash::Hash
+{
+    let index : HashMap<&T, usize> =
+        prev.iter().enumerate().map(|(i, t)| (t, i)).collect();
+    next.iter().map(|t| match index.get(t) {
+        Some(i) => DeltaEntry::Same(*i),
+        None => DeltaEntry::New(t.clone()),
+    }).collect()
+}
+
+fn apply_entries<T: Clone>(prev: &Vec<T>, entries: Vec<DeltaEntry<T>>)
+    -> Result<Vec<T>, String> {
+    entries.into_iter().map(|e| match e {
+        DeltaEntry::Same(i) => prev.get(i).cloned().ok_or_else(||
+            format!("Delta refers to missing index {}", i)),
+        DeltaEntry::New(t) => Ok(t),
+    }).collect()
+}
+
+impl EngineState {
+    // Describe the changes from prev to self.
+    pub fn delta(&self, prev: &EngineState) -> StateDelta {
+        let changed = self.procs.iter()
+            .filter(|(pid, proc)| prev.procs.get(pid) != Some(proc))
+            .map(|(pid, proc)| (*pid, proc.clone()))
+            .collect();
+        let removed = prev.procs.keys()
+            .filter(|pid| !self.procs.contains_key(pid))
+            .cloned()
+            .collect();
+
+        StateDelta { prev: prev.beat,
+                     beat: self.beat,
+                     position: self.position,
+                     names: delta_entries(&prev.names, &self.names),
+                     progs: delta_entries(&prev.progs, &self.progs),
+                     changed: changed,
+                     removed: removed,
+                     sleeping: self.sleeping,
+                     buffers: self.buffers.clone(),
+                     crashed: self.crashed.clone(),
+                     clock: self.clock.clone() }
+    }
+
+    pub fn apply(&self, delta: StateDelta) -> Result<EngineState, String> {
+        if delta.prev != self.beat {
+            return Err(format!("Delta from beat {} applied to beat {}",
+                               delta.prev, self.beat));
+        }
+
+        let mut procs = self.procs.clone();
+        for pid in delta.removed {
+            procs.remove(&pid);
         }
+        procs.extend(delta.changed);
+
+        Ok(EngineState { beat: delta.beat,
+                         position: delta.position,
+                         names: apply_entries(&self.names, delta.names)?,
+                         progs: apply_entries(&self.progs, delta.progs)?,
+                         procs: procs,
+                         sleeping: delta.sleeping,
+                         buffers: delta.buffers,
+                         crashed: delta.crashed,
+                         clock: delta.clock })
     }
 }
 
@@ -81,54 +344,335 @@ unsafe impl Send for KillReq {}
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct KillResp { }
 
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BeatEvents {
+    pub beat: u64,
+    pub events: Vec<EventLog>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ChannelSendReq { pub values: Vec<u8> }
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct OpInfo {
+    pub opcode: u8,
+    pub name: String,
+    pub description: String
+}
+
+// A parsed note_filter. kind is one of basic, solo, random, up, down, bi
+// or pause; durations are its arguments in beats.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct FilterInfo {
+    pub kind: String,
+    pub durations: Vec<u64>
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ChannelInfo {
+    pub channel: u8,
+    pub port: String,
+    pub midi_channel: u8,
+    pub bank: Option<u16>,
+    pub program: Option<u8>, // zero based
+    pub pan: Option<u8>,
+    pub note_filter: Option<String>,
+    pub filter: Option<FilterInfo>
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ServerInfo {
+    pub version: String,
+    pub period: u64,
+    pub ops: Vec<OpInfo>,
+    pub charmap: String, // One char per byte value
+    pub channels: Vec<ChannelInfo>,
+    pub outbound: Vec<u8>
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ChannelSendResp { }
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TempoReq { pub bpm: f64 }
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TempoResp { pub bpm: f64 }
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TransportReq { pub running: bool }
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TransportResp { pub running: bool }
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ChannelReadResp { pub values: Vec<u8> }
+
+
+// Where funged is listening.
+#[derive(Debug, Clone)]
+pub enum Endpoint {
+    Http(String), // Base uri, ending in /
+    Unix(PathBuf)
+}
+
+impl Endpoint {
+    // A socket path, usually from NOISEFUNGE_SOCKET, takes precedence over
+    // host and port.
+    pub fn new(host: &str, port: &str, socket: Option<&str>) -> Self {
+        match socket {
+            Some(path) => Endpoint::Unix(PathBuf::from(path)),
+            None => Endpoint::Http(format!("http://{}:{}/", host, port)),
+        }
+    }
+}
+
+pub struct ApiResponse {
+    pub status: u16,
+    pub encoding: Encoding,
+    body: Box<dyn Read + Send>
+}
+
+impl ApiResponse {
+    pub fn is_success(&self) -> bool {
+        self.status >= 200 && self.status < 300
+    }
+
+    pub fn decode<T: DeserializeOwned>(mut self) -> Result<T, String> {
+        let mut bytes = Vec::new();
+        self.body.read_to_end(&mut bytes)
+            .map_err(|e| format!("HTTP request failed: {:?}", e))?;
+        self.encoding.decode(&bytes)
+    }
+
+    pub fn into_reader(self) -> Box<dyn Read + Send> {
+        self.body
+    }
+}
+
+// Bearer token sent with every request, if the server requires one.
+pub const TOKEN_VAR: &str = "NOISEFUNGE_TOKEN";
+
+// Makes requests to funged over either HTTP or a Unix socket. Requests over
+// the socket are plain HTTP/1.0, so responses are close-delimited.
+pub struct ApiClient {
+    endpoint: Endpoint,
+    token: Option<String>,
+    client: Client
+}
+
+impl ApiClient {
+    pub fn new(endpoint: &Endpoint, user_agent: &str) -> Self {
+        let client = Client::builder().user_agent(user_agent)
+                                      .timeout(None)
+                                      .build()
+                                      .expect("Failed to build client");
+        let token = std::env::var(TOKEN_VAR).ok().filter(|t| !t.is_empty());
+        ApiClient { endpoint: endpoint.clone(),
+                    token: token,
+                    client: client }
+    }
+
+    pub fn get(&self, path: &str, query: &[(&str, String)], accept: Encoding,
+               timeout: Option<Duration>) -> Result<ApiResponse, String> {
+        self.request("GET", path, query, accept, None, timeout)
+    }
+
+    pub fn post<T: Serialize>(&self, path: &str, body: &T,
+                              timeout: Option<Duration>)
+        -> Result<ApiResponse, String> {
+        let body = serde_json::to_vec(body).unwrap();
+        self.request("POST", path, &[], Encoding::Json, Some(body), timeout)
+    }
+
+    fn request(&self, method: &str, path: &str, query: &[(&str, String)],
+               accept: Encoding, body: Option<Vec<u8>>,
+               timeout: Option<Duration>) -> Result<ApiResponse, String> {
+        match &self.endpoint {
+            Endpoint::Http(base) => {
+                let url = format!("{}{}", base, path);
+                let mut request = match method {
+                    "POST" => self.client.post(&url),
+                    _ => self.client.get(&url),
+                };
+                request = request.query(query).header(ACCEPT, accept.accept());
+                if let Some(token) = &self.token {
+                    request = request.bearer_auth(token);
+                }
+                if let Some(body) = body {
+                    request = request.header(CONTENT_TYPE, "application/json")
+                                     .body(body);
+                }
+                if let Some(timeout) = timeout {
+                    request = request.timeout(timeout);
+                }
+                let response = request.send().map_err(|e|
+                    format!("HTTP request failed: {:?}", e))?;
+                let encoding = Encoding::from_content_type(
+                    response.headers().get(CONTENT_TYPE)
+                                      .and_then(|v| v.to_str().ok()));
+                Ok(ApiResponse { status: response.status().as_u16(),
+                                 encoding: encoding,
+                                 body: Box::new(response) })
+            },
+            Endpoint::Unix(socket) => {
+                unix_request(socket, method, path, query, accept,
+                             self.token.as_deref(), body, timeout).map_err(|e|
+                    format!("HTTP request failed: {:?}", e))
+            }
+        }
+    }
+}
+
+fn unix_request(socket: &PathBuf, method: &str, path: &str,
+                query: &[(&str, String)], accept: Encoding,
+                token: Option<&str>, body: Option<Vec<u8>>,
+                timeout: Option<Duration>) -> std::io::Result<ApiResponse> {
+    use std::io::{Error, ErrorKind};
+
+    let url = Url::parse_with_params(&format!("http://localhost/{}", path),
+                                     query)
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    let target = match url.query() {
+        Some(q) if !q.is_empty() => format!("{}?{}", url.path(), q),
+        _ => url.path().to_string(),
+    };
+
+    let mut stream = UnixStream::connect(socket)?;
+    stream.set_read_timeout(timeout)?;
+    stream.set_write_timeout(timeout)?;
+
+    let mut head = format!("{} {} HTTP/1.0\r\nHost: localhost\r\n\
+                            Accept: {}\r\n", method, target, accept.accept());
+    if let Some(token) = token {
+        head.push_str(&format!("Authorization: Bearer {}\r\n", token));
+    }
+    if let Some(body) = &body {
+        head.push_str(&format!("Content-Type: application/json\r\n\
+                                Content-Length: {}\r\n", body.len()));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes())?;
+    if let Some(body) = &body {
+        stream.write_all(body)?;
+    }
+    stream.flush()?;
+
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let status = line.split_whitespace().nth(1)
+                     .and_then(|s| s.parse::<u16>().ok())
+                     .ok_or_else(|| Error::new(ErrorKind::InvalidData,
+                                               "Bad status line"))?;
+
+    let mut encoding = Encoding::Json;
+    let mut length = None;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 { break }
+        let line = line.trim_end();
+        if line.is_empty() { break }
+        let mut parts = line.splitn(2, ':');
+        let key = parts.next().unwrap_or("").trim();
+        let value = parts.next().unwrap_or("").trim();
+        if key.eq_ignore_ascii_case("Content-Type") {
+            encoding = Encoding::from_content_type(Some(value));
+        } else if key.eq_ignore_ascii_case("Content-Length") {
+            length = value.parse::<u64>().ok();
+        }
+    }
+
+    let body : Box<dyn Read + Send> = match length {
+        Some(len) => Box::new(reader.take(len)),
+        None => Box::new(reader),
+    };
+    Ok(ApiResponse { status: status,
+                     encoding: encoding,
+                     body: body })
+}
+
+pub fn get_process(client: &ApiClient, pid: u64)
+    -> Result<ProcDetail, String> {
+
+    let response = client.get(&format!("process/{}", pid), &[],
+                              Encoding::Json,
+                              Some(Duration::from_secs(4)))?;
+    if !response.is_success() {
+        return Err(format!("Bad status code: {}", response.status));
+    }
+    response.decode()
+}
+
+pub fn get_info(client: &ApiClient) -> Result<ServerInfo, String> {
+    let response = client.get("info", &[], Encoding::Json,
+                              Some(Duration::from_secs(4)))?;
+    if !response.is_success() {
+        return Err(format!("Bad status code: {}", response.status));
+    }
+    response.decode()
+}
 
 pub struct FungeClient(
     Arc<(Mutex<Option<Result<EngineState, String>>>, Condvar)>);
 
 impl FungeClient {
-    pub fn new(baseuri: &str) -> Self {
+    pub fn new(endpoint: &Endpoint) -> Self {
         let mtx = Mutex::new(None);
         let cond = Condvar::new();
         let arc = Arc::new((mtx, cond));
         let arc2 = Arc::clone(&arc);
-        let basereq = format!("{}state", baseuri);
+        let client = ApiClient::new(endpoint, "nfbuffer");
 
         thread::spawn(move || {
             let lock = &arc.0;
             let cond = &arc.1;
-            let mut prev = 0;
+            let mut current : Option<EngineState> = None;
             let mut delay = false;
-            let client = Client::builder().user_agent("nfbuffer")
-                                          .build()
-                                          .expect("Failed to build client");
             loop {
                 if delay {
                     delay = false;
                     thread::sleep(Duration::from_secs(1));
                 };
 
-                let request = client.get(&basereq)
-                                    .query(&[("prev", prev.to_string())])
-                                    .timeout(Duration::from_secs(4))
-                                    .build()
-                                    .expect("Failed to build client");
-                let response = client.execute(request);
+                // Without delta the server sends a full EngineState. With
+                // delta, it sends a StateUpdate relative to that beat.
+                let query = match &current {
+                    Some(st) => vec![("delta", st.beat.to_string())],
+                    None => Vec::new(),
+                };
+                let response = client.get("state", &query, Encoding::MsgPack,
+                                          Some(Duration::from_secs(4)));
                 let msg = match response {
                     Ok(response) => {
-                        if response.status().is_success() {
-                            response.json().map_err(|e|
-                                format!("Serialization error: {:?}", e))
-                                .map(|s: EngineState| { prev = s.beat; s })
-                        } else {
+                        if !response.is_success() {
                             delay = true;
-                            prev = 0;
-                            Err(format!("Bad status code: {:?}",
-                                        response.status()))
+                            Err(format!("Bad status code: {}",
+                                        response.status))
+                        } else {
+                            match &current {
+                                Some(st) => response.decode()
+                                    .and_then(|u| match u {
+                                        StateUpdate::Full(s) => Ok(s),
+                                        StateUpdate::Delta(d) => st.apply(d),
+                                    }),
+                                None => response.decode(),
+                            }
                         }
                     }
                     Err(e) => {
                         delay = true;
-                        Err(format!("HTTP request failed: {:?}", e))
+                        Err(e)
+                    }
+                };
+                let msg = match msg {
+                    Ok(s) => {
+                        current = Some(s.clone());
+                        Ok(s)
+                    },
+                    Err(e) => {
+                        current = None;
+                        Err(e)
                     }
                 };
                 let mut val = lock.lock().unwrap();
@@ -161,3 +705,120 @@ impl FungeClient {
     }
 
 }
+
+// Follows the event stream, reconnecting with since=beat so that no events
+// are missed between connections.
+pub struct EventClient(Receiver<Result<BeatEvents, String>>);
+
+impl EventClient {
+    pub fn new(endpoint: &Endpoint) -> Self {
+        let (snd, rcv) = bounded(1024);
+        let client = ApiClient::new(endpoint, "nfevents");
+
+        thread::spawn(move || {
+            let mut since : Option<u64> = None;
+            loop {
+                let query = match since {
+                    Some(since) => vec![("since", since.to_string())],
+                    None => Vec::new(),
+                };
+                let err = match client.get("events", &query, Encoding::MsgPack,
+                                           None) {
+                    Ok(response) if response.is_success() &&
+                        response.encoding == Encoding::MsgPack => {
+                        // A stream of BeatEvents, with nil as a keepalive.
+                        let mut reader = BufReader::new(response.into_reader());
+                        loop {
+                            let msg = match rmp_serde::from_read(&mut reader) {
+                                Ok(Some(evs)) => {
+                                    let evs : BeatEvents = evs;
+                                    since = Some(evs.beat);
+                                    evs
+                                },
+                                Ok(None) => continue,
+                                Err(_) => break,
+                            };
+                            if snd.send(Ok(msg)).is_err() { return }
+                        }
+                        "Event stream closed".to_string()
+                    },
+                    Ok(response) if response.is_success() => {
+                        let mut data = String::new();
+                        let reader = BufReader::new(response.into_reader());
+                        for line in reader.lines() {
+                            let line = match line {
+                                Ok(l) => l,
+                                Err(_) => break,
+                            };
+                            if line.starts_with("data:") {
+                                data.push_str(line[5..].trim_start());
+                                continue;
+                            }
+                            if !line.is_empty() || data.is_empty() {
+                                continue;
+                            }
+                            let msg = serde_json::from_str(&data)
+                                .map(|evs: BeatEvents| {
+                                    since = Some(evs.beat); evs })
+                                .map_err(|e| format!(
+                                    "Serialization error: {:?}", e));
+                            data.clear();
+                            if snd.send(msg).is_err() { return }
+                        }
+                        "Event stream closed".to_string()
+                    },
+                    Ok(response) => {
+                        // The server restarted or we fell too far behind.
+                        since = None;
+                        format!("Bad status code: {}", response.status)
+                    },
+                    Err(e) => e,
+                };
+                if snd.send(Err(err)).is_err() { return }
+                thread::sleep(Duration::from_secs(1));
+            }
+        });
+
+        EventClient(rcv)
+    }
+
+    pub fn get_events(&self, sleep_dur: Duration)
+        -> Option<Result<BeatEvents, String>> {
+
+        match self.0.recv_timeout(sleep_dur) {
+            Ok(msg) => Some(msg),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) =>
+                Some(Err("Event thread exited".to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::befunge::{Engine, Prog};
+
+    #[test]
+    fn delta_round_trip() {
+        let mut eng = Engine::new(24);
+        eng.make_process(Some("a".to_string()),
+                         Prog::parse("1.2.@").unwrap());
+        eng.make_process(Some("b".to_string()),
+                         Prog::parse(">3~@").unwrap());
+        let mut prev = eng.state();
+        for i in 0..6 {
+            eng.step();
+            if i == 2 {
+                eng.make_process(Some("c".to_string()),
+                                 Prog::parse("4.@").unwrap());
+            }
+            let next = eng.state();
+            let delta = next.delta(&prev);
+            let applied = prev.apply(delta.clone()).expect("apply failed");
+            assert_eq!(serde_json::to_value(&applied).unwrap(),
+                       serde_json::to_value(&next).unwrap());
+            assert!(next.apply(delta).is_err());
+            prev = next;
+        }
+    }
+}
@@ -0,0 +1,155 @@
+/*
+    Noisefunge Copyright (C) 2021 Rev. Johnny Healey <rev.null@gmail.com>
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use log::*;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use crossbeam_channel::{bounded, Sender, Receiver};
+
+use crate::clock::{spawn_clock, ClockHealth, Tempo};
+use crate::config::{BackendKind, FungedConfig};
+use crate::jack::{JackHandle, MidiMsg};
+use crate::midi_bridge::MidiSink;
+
+#[derive(Copy, Clone, Debug)]
+pub enum BackendError {
+    UnknownChannel(u8),
+    WriteFailed,
+    ReadFailed,
+    QueueFull,
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BackendError::UnknownChannel(ch) =>
+                write!(f, "unknown channel: {}", ch),
+            BackendError::WriteFailed =>
+                write!(f, "write failed"),
+            BackendError::ReadFailed =>
+                write!(f, "clock input keeps failing"),
+            BackendError::QueueFull =>
+                write!(f, "too many notes waiting"),
+        }
+    }
+}
+
+// What the clock is doing, in the order it happened.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ClockMsg {
+    Tick(u64), // Ticks (24 per quarter note) counted since startup
+    Start,
+    Stop,
+    Continue,
+    Position(u64), // Song position pointer, in sixteenth notes
+    Locate(u64), // Song position, in ticks
+}
+
+// Where funged gets its clock from and sends its notes to.
+pub trait Backend: MidiSink {
+    fn beats(&self) -> &Receiver<ClockMsg>;
+    fn errors(&self) -> &Receiver<BackendError>;
+    // Ticks dropped because the main loop fell behind.
+    fn missed(&self) -> u64;
+    // Called every so often to finish connecting ports.
+    fn maintain(&self);
+    // The tempo of the internal clock, if that's what's driving us.
+    fn tempo(&self) -> Option<Tempo>;
+    // Measurements of the incoming clock, for backends that take them.
+    fn health(&self) -> Option<ClockHealth>;
+}
+
+impl MidiSink for Box<dyn Backend> {
+    fn send_midi(&self, beat: u64, msg: MidiMsg) -> bool {
+        (**self).send_midi(beat, msg)
+    }
+
+    fn send_midi_at(&self, beat: u64, tick: f64, msg: MidiMsg) -> bool {
+        (**self).send_midi_at(beat, tick, msg)
+    }
+}
+
+pub fn open(conf: &FungedConfig) -> Box<dyn Backend> {
+    match conf.backend {
+        BackendKind::Jack => Box::new(JackHandle::new(conf)),
+        #[cfg(feature = "alsa")]
+        BackendKind::Alsa => Box::new(crate::alsa_seq::AlsaHandle::new(conf)),
+        #[cfg(not(feature = "alsa"))]
+        BackendKind::Alsa =>
+            panic!("noisefunge was built without the alsa feature"),
+        BackendKind::Null => Box::new(NullBackend::new(conf)),
+    }
+}
+
+// Ticks at the configured tempo and throws its output away.
+pub struct NullBackend {
+    beats: Receiver<ClockMsg>,
+    errors: Receiver<BackendError>,
+    _err_sender: Sender<BackendError>,
+    missed: Arc<AtomicU64>,
+    tempo: Tempo,
+}
+
+impl NullBackend {
+    pub fn new(conf: &FungedConfig) -> Self {
+        let tempo = Tempo::new(conf.tempo.unwrap_or(120.0));
+        let (beat_snd, beat_rcv) = bounded(16);
+        let (err_snd, err_rcv) = bounded(1);
+        let missed = Arc::new(AtomicU64::new(0));
+
+        info!("Null backend ticking at {} BPM", tempo.get());
+        spawn_clock(tempo.clone(), beat_snd, missed.clone());
+
+        NullBackend { beats: beat_rcv,
+                      errors: err_rcv,
+                      _err_sender: err_snd,
+                      missed: missed,
+                      tempo: tempo }
+    }
+}
+
+impl MidiSink for NullBackend {
+    fn send_midi(&self, _beat: u64, msg: MidiMsg) -> bool {
+        trace!("Null backend dropped {:?}", msg);
+        true
+    }
+}
+
+impl Backend for NullBackend {
+    fn beats(&self) -> &Receiver<ClockMsg> {
+        &self.beats
+    }
+
+    fn errors(&self) -> &Receiver<BackendError> {
+        &self.errors
+    }
+
+    fn missed(&self) -> u64 {
+        self.missed.load(Ordering::Relaxed)
+    }
+
+    fn maintain(&self) { }
+
+    fn tempo(&self) -> Option<Tempo> {
+        Some(self.tempo.clone())
+    }
+
+    fn health(&self) -> Option<ClockHealth> {
+        None
+    }
+}
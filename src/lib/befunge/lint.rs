@@ -0,0 +1,386 @@
+/*
+    Noisefunge Copyright (C) 2021 Rev. Johnny Healey <rev.null@gmail.com>
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Static analysis of programs. The walk follows every direction a process
+// could take from PC 0, tracking the top two stack values when they are
+// constants so that Goto, Call and QuantizeN operands can be checked.
+
+use std::collections::{BTreeSet, HashSet};
+use std::fmt;
+use super::ops::OpSet;
+use super::process::{Dir, Prog, PC};
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
+pub enum Hazard {
+    OffGrid(Dir),            // direction of travel
+    InvalidOpcode(u8),
+    GotoOutOfRange(u8, u8),  // x, y
+    CallOutOfRange(u8, u8),  // x, y
+    ZeroQuantize,
+}
+
+impl fmt::Display for Hazard {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Hazard::*;
+        match self {
+            OffGrid(dir) => {
+                let edge = match dir {
+                    Dir::L => "left",
+                    Dir::R => "right",
+                    Dir::U => "top",
+                    Dir::D => "bottom",
+                };
+                write!(f, "path runs off the {} edge", edge)
+            },
+            InvalidOpcode(c) => write!(f, "no opcode defined for 0x{:02X}", c),
+            GotoOutOfRange(x, y) =>
+                write!(f, "Goto to ({}, {}) is out of range", x, y),
+            CallOutOfRange(x, y) =>
+                write!(f, "Call to ({}, {}) is out of range", x, y),
+            ZeroQuantize => write!(f, "QuantizeN with a zero operand"),
+        }
+    }
+}
+
+// Why the walk could not see everything a program might do.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
+pub enum Limit {
+    SelfModifying,   // Put or Drop
+    UnknownJump,     // Goto or Call with a computed target
+    UnknownExecute,  // Execute with a computed opcode
+    UnknownDefop,    // Defop with a computed opcode
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Limit::*;
+        match self {
+            SelfModifying => write!(f, "program modifies itself"),
+            UnknownJump => write!(f, "jump target is not a constant"),
+            UnknownExecute => write!(f, "executed opcode is not a constant"),
+            UnknownDefop => write!(f, "defined opcode is not a constant"),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Reach {
+    No,
+    Op,    // executed as an opcode
+    Data,  // read in quote mode
+}
+
+pub struct Analysis {
+    pub reach: Vec<Reach>,
+    pub hazards: BTreeSet<(usize, Hazard)>,
+    pub limits: BTreeSet<(usize, Limit)>,
+    pub defined: BTreeSet<u8>,
+}
+
+impl Analysis {
+    // Distinct opcodes that some path executes.
+    pub fn reachable_ops(&self, prog: &Prog) -> BTreeSet<u8> {
+        self.reach.iter().enumerate()
+            .filter(|(_, r)| **r == Reach::Op)
+            .map(|(i, _)| prog.lookup(PC(i)))
+            .collect()
+    }
+}
+
+// Top two values of the data stack, when known: (second, top).
+type Stack = (Option<u8>, Option<u8>);
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+struct Node {
+    pc: usize,
+    dir: Dir,
+    quote: bool,
+    stack: Stack
+}
+
+enum Next {
+    Step(Dir, Stack),
+    Skip(Dir, Stack),
+    Jump(usize, Stack),
+    Quote(Stack),
+    End
+}
+
+fn push(s: Stack, c: Option<u8>) -> Stack {
+    (s.1, c)
+}
+
+fn pop(s: Stack) -> (Option<u8>, Stack) {
+    (s.1, (None, s.0))
+}
+
+fn pop2(s: Stack) -> (Option<u8>, Option<u8>, Stack) {
+    (s.1, s.0, (None, None))
+}
+
+struct Walk<'a> {
+    prog: &'a Prog,
+    ops: &'a OpSet,
+    analysis: Analysis,
+}
+
+impl<'a> Walk<'a> {
+
+    fn is_defined(&self, c: u8) -> bool {
+        self.ops.lookup(c).is_some() || self.analysis.defined.contains(&c)
+    }
+
+    fn hazard(&mut self, pc: usize, h: Hazard) -> Vec<Next> {
+        self.analysis.hazards.insert((pc, h));
+        vec![Next::End]
+    }
+
+    fn limit(&mut self, pc: usize, l: Limit) {
+        self.analysis.limits.insert((pc, l));
+    }
+
+    // Ways a process can continue after executing opcode c at pc.
+    fn effect(&mut self, pc: usize, dir: Dir, c: u8, s: Stack) -> Vec<Next> {
+        use Next::*;
+        if !self.is_defined(c) {
+            return self.hazard(pc, Hazard::InvalidOpcode(c));
+        }
+        let binary = |s: Stack, f: fn(u8, u8) -> Option<u8>| {
+            let (x, y, s) = pop2(s);
+            push(s, x.zip(y).and_then(|(x, y)| f(x, y)))
+        };
+        match c {
+            32 | 113 | 90 => vec![Step(dir, s)],
+            60 => vec![Step(Dir::L, s)],
+            62 => vec![Step(Dir::R, s)],
+            94 => vec![Step(Dir::U, s)],
+            118 => vec![Step(Dir::D, s)],
+            63 => [Dir::L, Dir::R, Dir::U, Dir::D].iter()
+                      .map(|d| Step(*d, s)).collect(),
+            34 => vec![Quote(s)],
+            64 | 93 => vec![End],
+            48..=57 => vec![Step(dir, push(s, Some(c - 48)))],
+            65..=70 => vec![Step(dir, push(s, Some(c - 55)))],
+            104 => vec![Step(dir, binary(s, |x, y|
+                                        Some((x << 4).wrapping_add(y))))],
+            110 => vec![Step(dir, binary(s, |x, y|
+                                        Some(x.wrapping_mul(12)
+                                              .wrapping_add(y))))],
+            43 => vec![Step(dir, binary(s, |x, y| Some(y.wrapping_add(x))))],
+            45 => vec![Step(dir, binary(s, |x, y| Some(y.wrapping_sub(x))))],
+            42 => vec![Step(dir, binary(s, |x, y| Some(y.wrapping_mul(x))))],
+            47 => vec![Step(dir, binary(s, |x, y| y.checked_div(x)))],
+            37 => vec![Step(dir, binary(s, |x, y| y.checked_rem(x)))],
+            61 => vec![Step(dir, binary(s, |x, y| Some((y == x) as u8)))],
+            96 => vec![Step(dir, binary(s, |x, y| Some((y > x) as u8)))],
+            33 => {
+                let (x, s) = pop(s);
+                vec![Step(dir, push(s, x.map(|x| (x == 0) as u8)))]
+            },
+            58 => vec![Step(dir, (s.1, s.1))],
+            92 => vec![Step(dir, (s.1, s.0))],
+            36 | 38 | 44 | 115 | 117 | 119 | 120 | 121 =>
+                vec![Step(dir, pop(s).1)],
+            46 => vec![Step(dir, pop2(s).2)],
+            126 => vec![Step(dir, push(pop(s).1, None))],
+            78 | 102 | 85 | 87 | 88 | 89 => vec![Step(dir, push(s, None))],
+            82 | 103 => vec![Step(dir, push(pop2(s).2, None))],
+            95 | 124 | 39 => {
+                let (x, s) = pop(s);
+                let (zero, other) = match c {
+                    95 => (Step(Dir::R, s), Step(Dir::L, s)),
+                    124 => (Step(Dir::D, s), Step(Dir::U, s)),
+                    _ => (Step(dir, s), Skip(dir, s)),
+                };
+                match x {
+                    Some(0) => vec![zero],
+                    Some(_) => vec![other],
+                    None => vec![zero, other],
+                }
+            },
+            35 => vec![Skip(dir, s)],
+            81 => match pop(s) {
+                (Some(0), _) => self.hazard(pc, Hazard::ZeroQuantize),
+                (_, s) => vec![Step(dir, s)],
+            },
+            91 => {
+                // The process ends here, but the body after the Defop runs
+                // whenever the new opcode is called.
+                match pop(s).0 {
+                    Some(op) => { self.analysis.defined.insert(op); },
+                    None => self.limit(pc, Limit::UnknownDefop),
+                }
+                vec![Step(dir, (None, None))]
+            },
+            101 => match pop(s) {
+                (Some(op), s) => self.effect(pc, dir, op, s),
+                (None, _) => {
+                    self.limit(pc, Limit::UnknownExecute);
+                    vec![Step(dir, (None, None))]
+                },
+            },
+            71 | 99 => {
+                let (y, x, s) = pop2(s);
+                let (x, y) = match (x, y) {
+                    (Some(x), Some(y)) => (x, y),
+                    _ => {
+                        self.limit(pc, Limit::UnknownJump);
+                        return vec![End];
+                    },
+                };
+                let target = self.prog.xy_to_pc(x as usize, y as usize);
+                match (c, target) {
+                    (71, None) =>
+                        self.hazard(pc, Hazard::GotoOutOfRange(x, y)),
+                    (_, None) =>
+                        self.hazard(pc, Hazard::CallOutOfRange(x, y)),
+                    (71, Some(PC(t))) => vec![Jump(t, s)],
+                    (_, Some(t)) => {
+                        let op = self.prog.lookup(t);
+                        self.effect(pc, dir, op, s)
+                    },
+                }
+            },
+            112 | 59 => {
+                self.limit(pc, Limit::SelfModifying);
+                vec![Step(dir, (None, None))]
+            },
+            _ => vec![Step(dir, (None, None))],
+        }
+    }
+
+    // Where a process at pc ends up after moving once in dir.
+    fn step(&mut self, pc: usize, dir: Dir) -> Option<usize> {
+        let w = self.prog.cols();
+        let h = self.prog.rows();
+        let next = match dir {
+            Dir::L if pc % w != 0 => Some(pc - 1),
+            Dir::R if pc % w != w - 1 => Some(pc + 1),
+            Dir::U if pc / w != 0 => Some(pc - w),
+            Dir::D if pc / w != h - 1 => Some(pc + w),
+            _ => None,
+        };
+        if next.is_none() {
+            self.hazard(pc, Hazard::OffGrid(dir));
+        }
+        next
+    }
+
+    fn run(&mut self) {
+        let start = Node { pc: 0, dir: Dir::R, quote: false,
+                           stack: (None, None) };
+        let mut seen = HashSet::new();
+        let mut queue = vec![start];
+        seen.insert(start);
+
+        while let Some(node) = queue.pop() {
+            let c = self.prog.lookup(PC(node.pc));
+            let nexts = if node.quote {
+                if c == 34 {
+                    self.analysis.reach[node.pc] = Reach::Op;
+                    vec![Next::Step(node.dir, node.stack)]
+                } else {
+                    if self.analysis.reach[node.pc] == Reach::No {
+                        self.analysis.reach[node.pc] = Reach::Data;
+                    }
+                    vec![Next::Quote(push(node.stack, Some(c)))]
+                }
+            } else {
+                self.analysis.reach[node.pc] = Reach::Op;
+                self.effect(node.pc, node.dir, c, node.stack)
+            };
+
+            for next in nexts {
+                let (pc, dir, quote, stack) = match next {
+                    Next::End => continue,
+                    Next::Jump(pc, s) => (Some(pc), node.dir, false, s),
+                    Next::Step(d, s) => (self.step(node.pc, d), d, false, s),
+                    Next::Quote(s) =>
+                        (self.step(node.pc, node.dir), node.dir, true, s),
+                    Next::Skip(d, s) => {
+                        let pc = self.step(node.pc, d)
+                                     .and_then(|pc| self.step(pc, d));
+                        (pc, d, false, s)
+                    },
+                };
+                if let Some(pc) = pc {
+                    let n = Node { pc: pc, dir: dir, quote: quote,
+                                   stack: stack };
+                    if seen.insert(n) {
+                        queue.push(n);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Walk every path through prog. Opcodes that the program defines with a
+// constant Defop are treated as valid, which can take a few passes.
+pub fn analyze(prog: &Prog, ops: &OpSet) -> Analysis {
+    let mut defined = BTreeSet::new();
+    loop {
+        let mut walk = Walk {
+            prog: prog,
+            ops: ops,
+            analysis: Analysis { reach: vec![Reach::No; prog.cols() *
+                                                         prog.rows()],
+                                 hazards: BTreeSet::new(),
+                                 limits: BTreeSet::new(),
+                                 defined: defined.clone() }
+        };
+        walk.run();
+        if walk.analysis.defined == defined {
+            return walk.analysis;
+        }
+        defined = walk.analysis.defined;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hazards(src: &str) -> Vec<(usize, Hazard)> {
+        let prog = Prog::parse(src).unwrap();
+        analyze(&prog, &OpSet::default()).hazards.into_iter().collect()
+    }
+
+    #[test]
+    fn hazards_found() {
+        use Hazard::*;
+        assert_eq!(hazards("1.@"), vec![]);
+        assert_eq!(hazards("12"), vec![(1, OffGrid(Dir::R))]);
+        assert_eq!(hazards("1k@"), vec![(1, InvalidOpcode(b'k'))]);
+        assert_eq!(hazards("09G"), vec![(2, GotoOutOfRange(0, 9))]);
+        assert_eq!(hazards("0Q@"), vec![(1, ZeroQuantize)]);
+        assert_eq!(hazards("v\n_@\n@"), vec![(2, OffGrid(Dir::L))]);
+        assert_eq!(hazards("30G@k"), vec![]);
+        assert_eq!(hazards("\"k\"@"), vec![]);
+    }
+
+    #[test]
+    fn reach() {
+        let prog = Prog::parse("\"a\"#k@ x").unwrap();
+        let analysis = analyze(&prog, &OpSet::default());
+        assert_eq!(analysis.reach,
+                   vec![Reach::Op, Reach::Data, Reach::Op, Reach::Op,
+                        Reach::No, Reach::Op, Reach::No, Reach::No]);
+        let ops: Vec<u8> = analysis.reachable_ops(&prog).into_iter().collect();
+        assert_eq!(ops, vec![b'"', b'#', b'@']);
+    }
+}
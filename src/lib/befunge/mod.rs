@@ -18,10 +18,12 @@
 mod process;
 mod ops;
 mod charmap;
+pub mod lint;
 pub use self::process::*;
 pub use self::ops::*;
 pub use self::charmap::*;
-use crate::api::{EngineState, ProcState, KillReq};
+use crate::api::{ClockState, EngineState, ProcState, ProcDetail, FrameState,
+                 KillReq, OpInfo};
 
 use arr_macro::arr;
 use std::collections::{BTreeMap, HashSet, HashMap, VecDeque};
@@ -33,7 +35,7 @@ use serde::{Serialize, Deserialize};
 enum MessageQueue {
     Empty,
     ReadBlocked(VecDeque<u64>),
-    WriteBlocked(VecDeque<(u64, u8)>)
+    WriteBlocked(VecDeque<(Option<u64>, u8)>) // None for external writers
 }
 
 impl MessageQueue {
@@ -50,7 +52,7 @@ impl MessageQueue {
                 q.is_empty()
             },
             MessageQueue::WriteBlocked(ref mut q) => {
-                q.retain(|(p, _)| f(p));
+                q.retain(|(p, _)| p.as_ref().map_or(true, |p| f(p)));
                 q.is_empty()
             },
             _ => { true }
@@ -58,7 +60,7 @@ impl MessageQueue {
         if empty { *self = MessageQueue::Empty };
     }
 
-    pub fn read(&mut self, pid: u64) -> Option<(u64, u8)> {
+    pub fn read(&mut self, pid: u64) -> Option<(Option<u64>, u8)> {
         match self {
             MessageQueue::ReadBlocked(q) => {
                 q.push_back(pid);
@@ -80,7 +82,16 @@ impl MessageQueue {
         }
     }
 
-    pub fn write(&mut self, pid: u64, c: u8) -> Option<u64> {
+    // Values waiting here from external writers.
+    pub fn external(&self) -> usize {
+        match self {
+            MessageQueue::WriteBlocked(q) =>
+                q.iter().filter(|(p, _)| p.is_none()).count(),
+            _ => 0
+        }
+    }
+
+    pub fn write(&mut self, pid: Option<u64>, c: u8) -> Option<u64> {
         match self {
             MessageQueue::ReadBlocked(q) => {
                 let res = q.pop_front().expect("Invalid ReadBlocked queue");
@@ -103,8 +114,15 @@ impl MessageQueue {
     }
 }
 
+// Maximum number of unread values held for an outbound channel.
+const OUTBOUND_LIMIT: usize = 1024;
+
+// Maximum number of sent values waiting on a channel for a reader.
+pub const INJECT_LIMIT: usize = 1024;
+
 pub struct Engine {
     beat: u64,
+    position: u64, // Beat within the song, which quantizing follows
     freq: u64,
     next_pid: u64,
     progs: HashSet<Rc<Prog>>,
@@ -114,9 +132,12 @@ pub struct Engine {
     active: Vec<u64>,
     sleeping: Vec<(u64, u32)>,
     kill_requests: Vec<KillReq>,
+    injected: Vec<(u8, u8)>,
+    outbound: BTreeMap<u8, VecDeque<u8>>,
     ops: OpSet,
     charmap: CharMap,
-    crash_log: Vec<(u64, CrashReason)>
+    crash_log: Vec<(u64, CrashReason)>,
+    reaped: HashMap<u64, Rc<str>> // Names of processes removed last step
 }
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
@@ -124,7 +145,7 @@ pub enum EventLog {
     NewProcess(u64),
     PrintChar(u64, u8),
     PrintNum(u64, u8),
-    Play(Note),
+    Play(u64, Note),
     Finished(u64),
     Crashed(u64, CrashReason),
     Killed(u64)
@@ -133,6 +154,7 @@ pub enum EventLog {
 impl Engine {
     pub fn new(period: u64) -> Engine {
         Engine { beat: 0,
+                 position: 0,
                  freq: 24 / period,
                  next_pid: 1,
                  progs: HashSet::new(),
@@ -142,9 +164,12 @@ impl Engine {
                  active: Vec::new(),
                  sleeping: Vec::new(),
                  kill_requests: Vec::new(),
+                 injected: Vec::new(),
+                 outbound: BTreeMap::new(),
                  ops: OpSet::default(),
                  charmap: CharMap::default(),
-                 crash_log: Vec::new() }
+                 crash_log: Vec::new(),
+                 reaped: HashMap::new() }
     }
 
     fn new_pid(&mut self) -> u64 {
@@ -157,6 +182,31 @@ impl Engine {
         self.kill_requests.push(req);
     }
 
+    // Queue values to be sent to a channel on the next step, as if they had
+    // been sent by a process that never blocks. Refused if that would leave
+    // more than INJECT_LIMIT of them waiting on the channel.
+    pub fn send(&mut self, ch: u8, values: &[u8]) -> bool {
+        let waiting = self.injected.iter().filter(|(c, _)| *c == ch).count()
+                      + self.buffers[ch as usize].external();
+        if waiting + values.len() > INJECT_LIMIT {
+            return false;
+        }
+        for c in values {
+            self.injected.push((ch, *c));
+        }
+        true
+    }
+
+    // Values sent to an outbound channel are held for reading by
+    // read_outbound instead of blocking the sender.
+    pub fn add_outbound(&mut self, ch: u8) {
+        self.outbound.entry(ch).or_insert_with(|| VecDeque::new());
+    }
+
+    pub fn read_outbound(&mut self, ch: u8) -> Option<Vec<u8>> {
+        self.outbound.get_mut(&ch).map(|q| q.drain(..).collect())
+    }
+
     pub fn make_process(&mut self, name: Option<String>, prog: Prog) -> u64 {
         let pid = self.new_pid();
 
@@ -177,7 +227,7 @@ impl Engine {
             ret
         });
 
-        let proc = Process::new(pid, name, prog);
+        let proc = Process::new(pid, name, prog, self.beat);
 
         self.procs.insert(pid, proc);
         self.active.push(pid);
@@ -191,8 +241,11 @@ impl Engine {
         let mut kill_reqs = mem::take(&mut self.kill_requests);
         let mut dead = Vec::new();
         let oldbeat = self.beat;
+        let oldpos = self.position;
         self.beat += 1;
+        self.position += 1;
         self.crash_log = Vec::new();
+        self.reaped = HashMap::new();
 
         let mut all_killed = false;
         let mut killed = HashSet::new();
@@ -217,13 +270,17 @@ impl Engine {
         }
 
         if all_killed {
-            for pid in self.procs.keys() {
+            for (pid, proc) in &self.procs {
                 log.push(EventLog::Killed(*pid));
+                if let Some(name) = &proc.name {
+                    self.reaped.insert(*pid, Rc::clone(name));
+                }
             }
             self.procs = BTreeMap::new();
-            for i in 0..255 {
-                self.buffers[i].reset();
+            for buf in self.buffers.iter_mut() {
+                buf.reset();
             }
+            self.injected.clear();
             self.process_names = HashMap::new();
             return (oldbeat, log)
         }
@@ -287,6 +344,15 @@ impl Engine {
             }
         }
 
+        for (ch, c) in mem::take(&mut self.injected) {
+            if let Some(blpid) = self.buffers[ch as usize].write(None, c) {
+                let blproc = self.procs.get_mut(&blpid)
+                    .expect("Blocked process not found");
+                blproc.resume(Some(c));
+                active.push(blpid);
+            }
+        }
+
         while !active.is_empty() {
             let mut next_active = Vec::new();
 
@@ -310,7 +376,7 @@ impl Engine {
                     ProcessState::Trap(Syscall::Fork) => {
                         let pid = self.next_pid;
                         self.next_pid += 1;
-                        let mut p2 = proc.fork(pid);
+                        let mut p2 = proc.fork(pid, oldbeat);
                         proc.resume(Some(0));
                         p2.resume(Some(1));
                         next_active.push(proc.pid);
@@ -337,12 +403,12 @@ impl Engine {
                     },
                     ProcessState::Trap(Syscall::Quantize(q)) => {
                         let q = *q as u64;
-                        let quarter = oldbeat / self.freq;
+                        let quarter = oldpos / self.freq;
                         let needed = match quarter % q {
                             0 => 0,
                             n => q - n
                         };
-                        let sub = match oldbeat % self.freq {
+                        let sub = match oldpos % self.freq {
                             0 => 0,
                             n => self.freq - n
                         };
@@ -357,8 +423,17 @@ impl Engine {
                     ProcessState::Trap(Syscall::Send(chan, c)) => {
                         let i = *chan as usize;
                         let c = *c;
+                        if let Some(q) = self.outbound.get_mut(chan) {
+                            if q.len() >= OUTBOUND_LIMIT {
+                                q.pop_front();
+                            }
+                            q.push_back(c);
+                            proc.resume(None);
+                            next_active.push(proc.pid);
+                            continue;
+                        }
                         let buf = &mut self.buffers[i];
-                        match buf.write(proc.pid, c) {
+                        match buf.write(Some(proc.pid), c) {
                             Some(blpid) => {
                                 proc.resume(None);
                                 next_active.push(proc.pid);
@@ -377,10 +452,12 @@ impl Engine {
                             Some((blpid, c)) => {
                                 proc.resume(Some(c));
                                 next_active.push(proc.pid);
-                                let blproc = self.procs.get_mut(&blpid)
-                                    .expect("Blocked process not found");
-                                blproc.resume(None);
-                                next_active.push(blproc.pid);
+                                if let Some(blpid) = blpid {
+                                    let blproc = self.procs.get_mut(&blpid)
+                                        .expect("Blocked process not found");
+                                    blproc.resume(None);
+                                    next_active.push(blproc.pid);
+                                }
                             },
                             None => {},
                         }
@@ -420,7 +497,7 @@ impl Engine {
                         next_active.push(proc.pid);
                     },
                     ProcessState::Trap(Syscall::Play(note)) => {
-                        log.push(EventLog::Play(*note));
+                        log.push(EventLog::Play(proc.pid, *note));
                         proc.set_play();
                         proc.resume(None);
                         next_active.push(proc.pid);
@@ -449,6 +526,7 @@ impl Engine {
                 Some(proc) => proc,
             };
             proc.name.map(|name| {
+                self.reaped.insert(pid, Rc::clone(&name));
                 if let Some(mut set) = self.process_names.remove(&name) {
                     set.remove(&pid);
                     if !set.is_empty() {
@@ -461,6 +539,47 @@ impl Engine {
         (oldbeat, log)
     }
 
+    // Name of a live process, or one that was removed in the last step.
+    pub fn process_name(&self, pid: u64) -> Option<String> {
+        match self.procs.get(&pid) {
+            Some(proc) => proc.name.as_ref().map(|n| n.to_string()),
+            None => self.reaped.get(&pid).map(|n| n.to_string()),
+        }
+    }
+
+    pub fn ops_info(&self) -> Vec<OpInfo> {
+        (0..=255).filter_map(|i| self.ops.lookup(i)).map(|op|
+            OpInfo { opcode: op.opcode,
+                     name: op.name.clone(),
+                     description: op.description.clone() }
+        ).collect()
+    }
+
+    pub fn ops(&self) -> &OpSet {
+        &self.ops
+    }
+
+    pub fn charmap(&self) -> &CharMap {
+        &self.charmap
+    }
+
+    pub fn set_charmap(&mut self, charmap: CharMap) {
+        self.charmap = charmap;
+    }
+
+    pub fn beat(&self) -> u64 {
+        self.beat
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    // Move within the song without disturbing the beat count.
+    pub fn set_position(&mut self, position: u64) {
+        self.position = position;
+    }
+
     pub fn state(&self) -> EngineState {
         let mut progs = Vec::new();
         let mut prog_map : HashMap<Rc<Prog>, usize> = HashMap::new();
@@ -515,15 +634,37 @@ impl Engine {
         }
 
         EngineState { beat: self.beat,
+                      position: self.position,
                       names: names,
                       progs: progs,
                       procs: procs,
                       sleeping: self.sleeping.len(),
                       buffers: buffers,
                       crashed: self.crash_log.clone(),
+                      clock: ClockState::default(),
                     }
     }
 
+    pub fn process_detail(&self, pid: u64) -> Option<ProcDetail> {
+        let proc = self.procs.get(&pid)?;
+
+        let call_stack = proc.call_stack().iter().map(|ps| {
+            let PC(pc) = ps.pc;
+            FrameState { prog: ps.memory.state_tuple(&self.charmap),
+                         pc: pc,
+                         dir: ps.dir }
+        }).collect();
+
+        Some(ProcDetail { pid: pid,
+                          name: proc.name.as_ref().map(|n| n.to_string()),
+                          spawned: proc.spawned(),
+                          state: proc.state().clone(),
+                          data_stack: proc.data_stack().clone(),
+                          call_stack: call_stack,
+                          note: *proc.get_note(),
+                          output: proc.get_output() })
+    }
+
 }
 
 #[cfg(test)]
@@ -587,11 +728,11 @@ mod tests {
             .expect("Parse failed."));
 
         expect_ordered(&mut eng, vec![
-            EventLog::Play(Note::new(1,60,40,9)),
-            EventLog::Play(Note::new(1,60,40,10)),
-            EventLog::Play(Note::new(1,60,41,10)),
-            EventLog::Play(Note::new(1,61,41,10)),
-            EventLog::Play(Note::new(2,61,41,10))], 50);
+            EventLog::Play(1, Note::new(1,60,40,9)),
+            EventLog::Play(1, Note::new(1,60,40,10)),
+            EventLog::Play(1, Note::new(1,60,41,10)),
+            EventLog::Play(1, Note::new(1,61,41,10)),
+            EventLog::Play(1, Note::new(2,61,41,10))], 50);
     }
 
     #[test]
@@ -684,6 +825,78 @@ mod tests {
             ], 10);
     }
 
+    #[test]
+    fn test_channel_send() {
+        let mut eng = Engine::new(24);
+        eng.make_process(None, Prog::parse(">0~&0~&@").unwrap());
+        eng.send(0, &[7]);
+        expect_ordered(&mut eng, vec![
+            EventLog::PrintNum(1, 7),
+            ], 10);
+
+        // Process is blocked on the second read.
+        eng.send(0, &[8, 9]);
+        expect_ordered(&mut eng, vec![
+            EventLog::PrintNum(1, 8),
+            EventLog::Finished(1),
+            ], 10);
+        assert_eq!(eng.state().buffers.get(&0), Some(&1));
+    }
+
+    #[test]
+    fn test_inject_limit() {
+        let mut eng = Engine::new(24);
+        assert!(eng.send(0, &[1; INJECT_LIMIT - 1]));
+        assert!(!eng.send(0, &[2, 3]));
+        assert!(eng.send(1, &[4]));
+
+        // Unread values still count once they're on the channel.
+        eng.step();
+        assert_eq!(eng.state().buffers.get(&0),
+                   Some(&(INJECT_LIMIT as i64 - 1)));
+        assert!(eng.send(0, &[2]));
+        assert!(!eng.send(0, &[3]));
+
+        eng.kill(KillReq::All);
+        eng.step();
+        assert_eq!(eng.state().buffers.get(&0), None);
+        assert!(eng.send(0, &[1; INJECT_LIMIT]));
+    }
+
+    #[test]
+    fn test_outbound() {
+        let mut eng = Engine::new(24);
+        eng.add_outbound(9);
+        eng.make_process(None, Prog::parse(">59.69.@").unwrap());
+        expect_ordered(&mut eng, vec![
+            EventLog::Finished(1),
+            ], 20);
+        assert_eq!(eng.read_outbound(9), Some(vec![5, 6]));
+        assert_eq!(eng.read_outbound(9), Some(vec![]));
+        assert_eq!(eng.read_outbound(8), None);
+    }
+
+    #[test]
+    fn test_process_detail() {
+        let mut eng = Engine::new(24);
+        eng.step();
+        let pid = eng.make_process(Some("blocked".to_string()),
+                                   Prog::parse(">12~@").unwrap());
+        for _i in 0..4 {
+            eng.step();
+        }
+
+        let detail = eng.process_detail(pid).expect("No process detail");
+        assert_eq!(detail.name, Some("blocked".to_string()));
+        assert_eq!(detail.spawned, 1);
+        assert_eq!(detail.state, ProcessState::Trap(Syscall::Receive(2)));
+        assert_eq!(detail.data_stack, vec![1]);
+        assert_eq!(detail.call_stack.len(), 1);
+        assert_eq!(detail.call_stack[0].pc, 3);
+        assert_eq!(detail.call_stack[0].dir, Dir::R);
+        assert!(eng.process_detail(pid + 1).is_none());
+    }
+
     #[test]
     fn test_quantize() {
         let mut eng = Engine::new(24);
@@ -696,4 +909,21 @@ mod tests {
         ], 100);
     }
 
+    #[test]
+    fn quantize_position() {
+        let mut eng = Engine::new(24);
+        eng.make_process(None, Prog::parse(">84Q&@").unwrap());
+        let beat = expect_ordered(&mut eng, vec![EventLog::PrintNum(1, 8)],
+                                  100);
+
+        let mut moved = Engine::new(24);
+        moved.set_position(3);
+        moved.make_process(None, Prog::parse(">84Q&@").unwrap());
+        let moved_beat = expect_ordered(&mut moved,
+                                        vec![EventLog::PrintNum(1, 8)], 100);
+        // Both wake on the same point in the bar, at different beats.
+        assert_ne!(moved_beat, beat);
+        assert_eq!((moved_beat + 3) % 4, beat % 4);
+    }
+
 }
@@ -21,8 +21,10 @@ use std::rc::Rc;
 use std::mem;
 use serde::{Serialize, Deserialize};
 use super::charmap::CharMap;
+use super::ops::OpSet;
 
-#[derive(Copy, Clone, Serialize, Deserialize)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Debug, Serialize,
+         Deserialize)]
 pub enum Dir { U, D, L, R }
 
 #[derive(Copy, Clone, Serialize, Deserialize)]
@@ -31,11 +33,48 @@ pub struct PC(pub usize);
 #[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize, Debug)]
 pub struct Prog { width : usize, data : Vec<u8> }
 
+// How program text maps to memory. Raw sources are stored as the bytes
+// they're written in. Charmap sources are written in the glyphs the viewers
+// show, so every byte value can be entered.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum SourceMode {
+    Raw,
+    Charmap
+}
+
+impl Default for SourceMode {
+    fn default() -> Self { SourceMode::Raw }
+}
+
+// Lines and columns are 1-based and columns count characters, not bytes.
+// Line lengths count the bytes that end up in memory.
 #[derive(Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize, Debug)]
 pub enum ParseError {
     Empty,
-    TooManyLines,
-    LineTooLong
+    TooManyLines(usize),              // line count
+    LineTooLong(usize, usize),        // line, length
+    GlyphInRaw(usize, usize, char),   // line, col, glyph
+    RawInCharmap(usize, usize, char),
+    NonAscii(usize, usize, char),     // strict only
+    UnknownGlyph(usize, usize, char),
+    Tab(usize, usize),                // strict only
+    CarriageReturn(usize, usize),     // strict only
+    UndefinedOpcode(usize, usize, u8) // strict only
+}
+
+impl ParseError {
+    // The (line, col) an error points at, if it has one.
+    pub fn position(&self) -> Option<(usize, usize)> {
+        use ParseError::*;
+        match *self {
+            Empty => None,
+            TooManyLines(_) => Some((256, 1)),
+            LineTooLong(l, _) => Some((l, 256)),
+            GlyphInRaw(l, c, _) | RawInCharmap(l, c, _) | NonAscii(l, c, _) |
+            UnknownGlyph(l, c, _) | Tab(l, c) | CarriageReturn(l, c) |
+            UndefinedOpcode(l, c, _) => Some((l, c)),
+        }
+    }
 }
 
 impl fmt::Display for ParseError {
@@ -43,8 +82,20 @@ impl fmt::Display for ParseError {
         use ParseError::*;
         match self {
             Empty => write!(f, "Empty program"),
-            TooManyLines => write!(f, "too many lines"),
-            LineTooLong => write!(f, "too long lines"),
+            TooManyLines(n) => write!(f, "{} lines (max 255)", n),
+            LineTooLong(_, n) =>
+                write!(f, "line is {} characters (max 255)", n),
+            GlyphInRaw(_, _, c) =>
+                write!(f, "charmap glyph {} in raw source", c),
+            RawInCharmap(_, _, c) =>
+                write!(f, "raw character {:?} in charmap source", c),
+            NonAscii(_, _, c) =>
+                write!(f, "non-ASCII character {} in raw source", c),
+            UnknownGlyph(_, _, c) => write!(f, "{} is not in the charmap", c),
+            Tab(_, _) => write!(f, "tab character"),
+            CarriageReturn(_, _) => write!(f, "carriage return"),
+            UndefinedOpcode(_, _, b) =>
+                write!(f, "no opcode defined for 0x{:02X}", b),
         }
     }
 }
@@ -52,28 +103,94 @@ impl fmt::Display for ParseError {
 impl Prog {
 
     pub fn parse(prog: &str) -> Result<Prog, ParseError> {
+        Prog::parse_mode(prog, SourceMode::Raw, CharMap::shared_default())
+    }
+
+    // Parse a program, rejecting characters that belong to the other mode so
+    // that a mix of raw text and glyphs isn't silently misread.
+    pub fn parse_mode(prog: &str, mode: SourceMode, cm: &CharMap)
+        -> Result<Prog, ParseError> {
+        Prog::parse_with(prog, mode, cm, None)
+    }
+
+    // Like parse_mode, but also reject tabs, carriage returns, non-ASCII raw
+    // text and bytes that have no op in `ops`. Text between a pair of quotes
+    // on one line is treated as data and may hold anything.
+    pub fn parse_strict(prog: &str, mode: SourceMode, cm: &CharMap,
+                        ops: &OpSet) -> Result<Prog, ParseError> {
+        Prog::parse_with(prog, mode, cm, Some(ops))
+    }
+
+    fn parse_with(prog: &str, mode: SourceMode, cm: &CharMap,
+                  strict: Option<&OpSet>) -> Result<Prog, ParseError> {
+        // Appends the bytes a character stands for.
+        let decode = |l: usize, col: usize, c: char, quoted: bool,
+                      bytes: &mut Vec<u8>| {
+            match mode {
+                SourceMode::Raw if c.is_ascii() => bytes.push(c as u8),
+                SourceMode::Raw if cm.lookup(c).is_some() =>
+                    return Err(ParseError::GlyphInRaw(l, col, c)),
+                SourceMode::Raw if strict.is_some() && !quoted =>
+                    return Err(ParseError::NonAscii(l, col, c)),
+                SourceMode::Raw => {
+                    let mut utf8 = [0; 4];
+                    bytes.extend_from_slice(c.encode_utf8(&mut utf8)
+                                             .as_bytes());
+                },
+                SourceMode::Charmap => match cm.lookup(c) {
+                    Some(b) => bytes.push(b),
+                    None if c.is_ascii() =>
+                        return Err(ParseError::RawInCharmap(l, col, c)),
+                    None => return Err(ParseError::UnknownGlyph(l, col, c)),
+                },
+            }
+            Ok(())
+        };
+
         if prog.len() == 0 {
             return Err(ParseError::Empty);
         }
         let mut lines = Vec::new();
         let mut longest = 0;
-        for line in prog.split("\n") {
-            lines.push(line);
-            longest = max(longest, line.bytes().count());
+        for (i, line) in prog.split("\n").enumerate() {
+            let l = i + 1;
+            let mut bytes = Vec::new();
+            let mut quoted = false;
+            for (j, c) in line.chars().enumerate() {
+                let col = j + 1;
+                if strict.is_some() {
+                    match c {
+                        '\t' => return Err(ParseError::Tab(l, col)),
+                        '\r' => return Err(ParseError::CarriageReturn(l, col)),
+                        _ => ()
+                    }
+                }
+                let start = bytes.len();
+                decode(l, col, c, quoted, &mut bytes)?;
+                if let (Some(ops), &[b]) = (strict, &bytes[start..]) {
+                    if b == b'"' {
+                        quoted = !quoted;
+                    } else if !quoted && ops.lookup(b).is_none() {
+                        return Err(ParseError::UndefinedOpcode(l, col, b));
+                    }
+                }
+            }
+            if bytes.len() > 255 {
+                return Err(ParseError::LineTooLong(l, bytes.len()));
+            }
+            longest = max(longest, bytes.len());
+            lines.push(bytes);
         }
         if longest == 0 {
             return Err(ParseError::Empty);
         }
         if lines.len() > 255 {
-            return Err(ParseError::TooManyLines);
-        }
-        if longest > 255 {
-            return Err(ParseError::LineTooLong);
+            return Err(ParseError::TooManyLines(lines.len()));
         }
         let mut mem = Vec::new();
         for line in lines {
             let mut count = 0;
-            for ch in line.bytes() {
+            for ch in line {
                 mem.push(ch);
                 count += 1;
             }
@@ -128,7 +245,7 @@ pub struct ProcessStack {
     pub dir: Dir
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub enum Syscall {
     Fork,
     Sleep(u32),
@@ -152,7 +269,7 @@ pub enum CrashReason {
     DivideByZero
 }
 
-#[derive(Clone, PartialEq, Eq, Debug)]
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub enum ProcessState {
     Running(bool),
     Trap(Syscall),
@@ -183,6 +300,7 @@ impl Note {
 pub struct Process {
     pub pid: u64,
     pub name: Option<Rc<str>>,
+    spawned: u64,
     data_stack: Vec<u8>,
     call_stack: Vec<ProcessStack>,
     state: ProcessState,
@@ -192,7 +310,8 @@ pub struct Process {
 }
 
 impl Process {
-    pub fn new(pid: u64, name: Option<Rc<str>>, prog: Rc<Prog>) -> Process {
+    pub fn new(pid: u64, name: Option<Rc<str>>, prog: Rc<Prog>,
+               beat: u64) -> Process {
         let st = ProcessStack { memory: prog,
                                 pc: PC(0),
                                 dir: Dir::R };
@@ -200,6 +319,7 @@ impl Process {
         stvec.push(st);
         Process { pid: pid,
                   name: name,
+                  spawned: beat,
                   data_stack: Vec::new(),
                   call_stack: stvec,
                   state: ProcessState::Running(false),
@@ -358,12 +478,17 @@ impl Process {
         Some(top.memory.data[i])
     }
 
-    pub fn fork(&self, newpid: u64) -> Self {
+    pub fn fork(&self, newpid: u64, beat: u64) -> Self {
         let mut new = self.clone();
         new.pid = newpid;
+        new.spawned = beat;
         return new
     }
 
+    pub fn spawned(&self) -> u64 {
+        self.spawned
+    }
+
     pub fn set_note(&mut self, note: Note) {
         self.note = note;
     }
@@ -401,6 +526,10 @@ impl Process {
         }
     }
 
+    pub fn data_stack(&self) -> &Vec<u8> {
+        &self.data_stack
+    }
+
     pub fn data_stack_size(&self) -> usize {
         self.data_stack.len()
     }
@@ -463,8 +592,59 @@ mod tests {
             too_many.push_str("a\n");
         }
 
-        assert_eq!(Prog::parse(&long_line), Err(LineTooLong));
-        assert_eq!(Prog::parse(&too_many), Err(TooManyLines));
+        assert_eq!(Prog::parse(&long_line), Err(LineTooLong(1, 256)));
+        assert_eq!(Prog::parse(&too_many), Err(TooManyLines(257)));
+
+        // Lines are measured in the bytes they take up.
+        let wide = "\u{e9}".repeat(128);
+        assert_eq!(Prog::parse(&wide), Err(LineTooLong(1, 256)));
+    }
+
+    #[test]
+    fn utf8_prog() {
+        let pr = Prog::parse("\"\u{e9}\">:#,_@").unwrap();
+        assert_eq!(pr.cols(), 10);
+        assert_eq!(&pr.data[..4], &[b'"', 0xc3, 0xa9, b'"']);
+    }
+
+    #[test]
+    fn charmap_prog() {
+        use ParseError::*;
+        let cm = CharMap::default();
+        let pr = Prog::parse(">1v\n@.<").unwrap();
+        let (w, glyphs) = pr.state_tuple(&cm);
+        let glyphs : Vec<char> = glyphs.chars().collect();
+        let glyphs = glyphs.chunks(w).map(|l| l.iter().collect::<String>())
+                           .collect::<Vec<String>>().join("\n");
+        assert_eq!(Prog::parse_mode(&glyphs, SourceMode::Charmap, &cm),
+                   Ok(pr));
+
+        let high = Prog::parse_mode(&cm[200].to_string(),
+                                    SourceMode::Charmap, &cm).unwrap();
+        assert_eq!(high.data, vec![200]);
+
+        assert_eq!(Prog::parse(&glyphs),
+                   Err(GlyphInRaw(1, 1, cm['>' as u8])));
+        assert_eq!(Prog::parse_mode(">", SourceMode::Charmap, &cm),
+                   Err(RawInCharmap(1, 1, '>')));
+        assert_eq!(Prog::parse("\u{e9}").map(|p| p.data),
+                   Ok(vec![0xc3, 0xa9]));
+    }
+
+    #[test]
+    fn strict_prog() {
+        use ParseError::*;
+        let cm = CharMap::default();
+        let ops = OpSet::default();
+        let strict = |p| Prog::parse_strict(p, SourceMode::Raw, &cm, &ops);
+        let quoted = "1.@\n\"k\"@";
+        assert_eq!(strict(quoted), Ok(Prog::parse(quoted).unwrap()));
+        assert_eq!(strict("1.@\r\n"), Err(CarriageReturn(1, 4)));
+        assert_eq!(strict("1\t.@"), Err(Tab(1, 2)));
+        assert_eq!(strict("1.\n @k"), Err(UndefinedOpcode(2, 3, b'k')));
+        assert_eq!(strict("1.@\n\u{e9}"), Err(NonAscii(2, 1, '\u{e9}')));
+        let quoted = "\"\u{e9}\"@";
+        assert_eq!(strict(quoted), Ok(Prog::parse(quoted).unwrap()));
     }
 
 }
@@ -0,0 +1,645 @@
+/*
+    Noisefunge Copyright (C) 2021 Rev. Johnny Healey <rev.null@gmail.com>
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// The internal clock, for running without an external MIDI clock.
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use crossbeam_channel::{Sender, TrySendError};
+
+use crate::backend::ClockMsg;
+
+// Clock ticks per quarter note, as in MIDI clock.
+pub const PPQN: f64 = 24.0;
+
+// Taps further apart than this start a new measurement.
+const TAP_RESET: Duration = Duration::from_secs(2);
+
+// Number of tap intervals averaged.
+const TAP_WINDOW: usize = 4;
+
+// Number of tick intervals the clock is measured over, a bar of 4/4.
+const METER_WINDOW: usize = 96;
+
+// The range of tempos the internal clock will run at.
+pub const MIN_BPM: f64 = 1.0;
+pub const MAX_BPM: f64 = 999.0;
+
+// Every tempo from a request, the config or taps goes through here. Anything
+// that isn't a positive number is refused, and the rest is clamped.
+pub fn clamp_bpm(bpm: f64) -> Option<f64> {
+    if bpm > 0.0 && bpm.is_finite() {
+        Some(bpm.max(MIN_BPM).min(MAX_BPM))
+    } else {
+        None
+    }
+}
+
+// Beats per minute and whether the clock is running, shared between the
+// clock and whoever sets them.
+#[derive(Clone, Debug)]
+pub struct Tempo(Arc<(AtomicU64, AtomicBool)>);
+
+impl Tempo {
+    pub fn new(bpm: f64) -> Self {
+        Tempo(Arc::new((AtomicU64::new(bpm.to_bits()), AtomicBool::new(true))))
+    }
+
+    pub fn get(&self) -> f64 {
+        f64::from_bits((self.0).0.load(Ordering::Relaxed))
+    }
+
+    pub fn set(&self, bpm: f64) {
+        (self.0).0.store(bpm.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn running(&self) -> bool {
+        (self.0).1.load(Ordering::Relaxed)
+    }
+
+    pub fn set_running(&self, running: bool) {
+        (self.0).1.store(running, Ordering::Relaxed);
+    }
+
+    fn tick_secs(&self) -> f64 {
+        60.0 / (self.get() * PPQN)
+    }
+}
+
+// Tempo from the average interval of the last few taps.
+#[derive(Default)]
+pub struct TapTempo {
+    taps: VecDeque<Instant>,
+}
+
+impl TapTempo {
+    pub fn new() -> Self {
+        TapTempo::default()
+    }
+
+    // Returns the tapped tempo once there are at least two taps.
+    pub fn tap(&mut self, now: Instant) -> Option<f64> {
+        if let Some(last) = self.taps.back() {
+            if now.duration_since(*last) > TAP_RESET {
+                self.taps.clear();
+            }
+        }
+        self.taps.push_back(now);
+        if self.taps.len() > TAP_WINDOW + 1 {
+            self.taps.pop_front();
+        }
+
+        let first = self.taps.front()?;
+        let span = now.duration_since(*first).as_secs_f64();
+        if self.taps.len() < 2 || span <= 0.0 {
+            return None;
+        }
+        clamp_bpm(60.0 * (self.taps.len() - 1) as f64 / span)
+    }
+}
+
+// Places ticks on frames, for clocks driven by an audio callback. Tempo
+// changes take effect from the next cycle, and a stopped clock starts again
+// at the beginning of a cycle.
+pub struct FrameClock {
+    tempo: Tempo,
+    next: f64,
+}
+
+pub struct FrameTicks {
+    next: f64,
+    step: f64,
+    frames: f64,
+}
+
+impl FrameTicks {
+    pub fn running(&self) -> bool {
+        self.next < f64::INFINITY
+    }
+}
+
+impl Iterator for FrameTicks {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        if self.next >= self.frames {
+            return None;
+        }
+        let t = self.next as u32;
+        self.next += self.step;
+        Some(t)
+    }
+}
+
+impl FrameClock {
+    pub fn new(tempo: Tempo) -> Self {
+        FrameClock { tempo: tempo, next: 0.0 }
+    }
+
+    // A clock whose first tick is the given number of frames into the next
+    // cycle.
+    pub fn starting_at(tempo: Tempo, next: f64) -> Self {
+        FrameClock { tempo: tempo, next: next }
+    }
+
+    // Frame offsets of the ticks in a cycle of the given length.
+    pub fn cycle(&mut self, frames: u32, rate: usize) -> FrameTicks {
+        let step = self.tempo.tick_secs() * rate as f64;
+        let frames = frames as f64;
+        if !self.tempo.running() {
+            self.next = 0.0;
+            return FrameTicks { next: f64::INFINITY, step: step,
+                                frames: frames };
+        }
+        let ticks = FrameTicks { next: self.next, step: step, frames: frames };
+        if self.next < frames {
+            let n = ((frames - self.next) / step).ceil();
+            self.next += n * step;
+        }
+        self.next -= frames;
+        ticks
+    }
+}
+
+// Follows a transport from its song position, measured in ticks, at the
+// start of each cycle. Position jumps are passed on as Locate, and the
+// transport starting and stopping as Continue and Stop.
+pub struct TransportSync {
+    expected: Option<f64>,
+    rolling: bool,
+}
+
+impl TransportSync {
+    pub fn new() -> Self {
+        // funged starts out running, so a stopped transport is news.
+        TransportSync { expected: None, rolling: true }
+    }
+
+    // Frame offsets of the ticks in this cycle, given the length of a tick
+    // in frames.
+    pub fn cycle<F>(&mut self, rolling: bool, pos: f64, step: f64,
+                    frames: u32, mut send: F) -> FrameTicks
+        where F: FnMut(ClockMsg)
+    {
+        let moved = match self.expected {
+            Some(e) => (pos - e).abs() > 0.5,
+            None => true,
+        };
+        if moved {
+            send(ClockMsg::Locate(pos.ceil() as u64));
+        }
+        if rolling != self.rolling {
+            self.rolling = rolling;
+            send(if rolling { ClockMsg::Continue } else { ClockMsg::Stop });
+        }
+
+        let frames = frames as f64;
+        if !rolling {
+            self.expected = Some(pos);
+            return FrameTicks { next: f64::INFINITY, step: step,
+                                frames: frames };
+        }
+        self.expected = Some(pos + frames / step);
+        FrameTicks { next: (pos.ceil() - pos) * step, step: step,
+                     frames: frames }
+    }
+}
+
+// Estimates tempo and jitter from the frame times ticks arrive at, and
+// notices when they stop arriving.
+pub struct ClockMeter {
+    last: Option<u32>,
+    intervals: VecDeque<f64>,
+}
+
+impl ClockMeter {
+    pub fn new() -> Self {
+        ClockMeter { last: None,
+                     intervals: VecDeque::with_capacity(METER_WINDOW) }
+    }
+
+    // Frame times wrap, so only the distance between them is used.
+    pub fn tick(&mut self, frame: u32) {
+        if let Some(last) = self.last {
+            if self.intervals.len() == METER_WINDOW {
+                self.intervals.pop_front();
+            }
+            self.intervals.push_back(frame.wrapping_sub(last) as f64);
+        }
+        self.last = Some(frame);
+    }
+
+    // Forget the last tick, so a stop isn't measured as a slow tick.
+    pub fn pause(&mut self) {
+        self.last = None;
+    }
+
+    // Mean tick interval, in frames.
+    pub fn interval(&self) -> Option<f64> {
+        if self.intervals.is_empty() {
+            return None;
+        }
+        Some(self.intervals.iter().sum::<f64>() / self.intervals.len() as f64)
+    }
+
+    pub fn bpm(&self, rate: usize) -> Option<f64> {
+        self.interval().map(|m| 60.0 * rate as f64 / (m * PPQN))
+    }
+
+    // Standard deviation of the tick interval, in milliseconds.
+    pub fn jitter(&self, rate: usize) -> Option<f64> {
+        let mean = self.interval()?;
+        let var = self.intervals.iter().map(|i| (i - mean).powi(2))
+                                .sum::<f64>() / self.intervals.len() as f64;
+        Some(var.sqrt() * 1000.0 / rate as f64)
+    }
+
+    // Time since the last tick, measured in ticks.
+    pub fn due(&self, now: u32) -> Option<f64> {
+        Some(now.wrapping_sub(self.last?) as f64 / self.interval()?)
+    }
+
+    // True once a quarter note's worth of ticks has gone missing.
+    pub fn stalled(&self, now: u32) -> bool {
+        self.due(now).map(|d| d > PPQN).unwrap_or(false)
+    }
+}
+
+// When an external clock comes back after a fallback stood in for it from
+// beat lost_at, the beat to count on from and how many of its ticks to skip
+// because the fallback got there first. due is the time since its last
+// tick, in ticks, as a ClockMeter has it.
+pub fn realign(lost_at: u64, due: Option<f64>, beat: u64) -> (u64, u64) {
+    let due = due.map(|d| d.round().max(1.0)).unwrap_or(1.0);
+    let target = lost_at + due as u64;
+    if target > beat {
+        (target - 1, 0)
+    } else {
+        (beat, beat + 1 - target)
+    }
+}
+
+// Places messages, given in order with the ticks they're due at, on frames
+// from the beat and frame of the last tick and the frames per tick. Calls
+// emit for each one that falls in a cycle of the given length, and returns
+// how many did, and the last tick with its frame counted from the next
+// cycle. With the clock stopped, or nothing ticking, everything goes out at
+// once.
+pub fn place<T, I, F>(msgs: I, last: (u64, f64), stopped: bool, step: f64,
+                      frames: u32, mut emit: F) -> (usize, (u64, f64))
+    where I: Iterator<Item=(f64, T)>, F: FnMut(u32, T)
+{
+    let (beat, frame) = last;
+    let mut prev = 0;
+    let mut done = 0;
+    for (tick, msg) in msgs {
+        let ahead = tick - beat as f64;
+        let at = if stopped || (ahead <= 0.0 && step == 0.0) {
+            0.0
+        } else if step == 0.0 {
+            f64::INFINITY
+        } else {
+            frame + ahead * step
+        };
+        if at >= frames as f64 {
+            break;
+        }
+        // Late messages go out first thing, and writes can't go back.
+        prev = cmp::max(prev, at.max(0.0) as u32);
+        emit(prev, msg);
+        done += 1;
+    }
+    (done, (beat, frame - frames as f64))
+}
+
+// The latest readings from a ClockMeter, and whether a fallback clock has
+// taken over, shared with whoever reports them. Unknown values are stored
+// as NaN.
+#[derive(Clone, Debug)]
+pub struct ClockHealth(Arc<(AtomicU64, AtomicU64, AtomicBool, AtomicBool)>);
+
+fn known(bits: &AtomicU64) -> Option<f64> {
+    let v = f64::from_bits(bits.load(Ordering::Relaxed));
+    if v.is_nan() { None } else { Some(v) }
+}
+
+impl ClockHealth {
+    pub fn new() -> Self {
+        ClockHealth(Arc::new((AtomicU64::new(f64::NAN.to_bits()),
+                              AtomicU64::new(f64::NAN.to_bits()),
+                              AtomicBool::new(false),
+                              AtomicBool::new(false))))
+    }
+
+    pub fn publish(&self, meter: &ClockMeter, rate: usize, now: u32,
+                   failover: bool) {
+        let nan = f64::NAN;
+        (self.0).0.store(meter.bpm(rate).unwrap_or(nan).to_bits(),
+                         Ordering::Relaxed);
+        (self.0).1.store(meter.jitter(rate).unwrap_or(nan).to_bits(),
+                         Ordering::Relaxed);
+        (self.0).2.store(meter.stalled(now), Ordering::Relaxed);
+        (self.0).3.store(failover, Ordering::Relaxed);
+    }
+
+    pub fn bpm(&self) -> Option<f64> {
+        known(&(self.0).0)
+    }
+
+    pub fn jitter(&self) -> Option<f64> {
+        known(&(self.0).1)
+    }
+
+    pub fn stalled(&self) -> bool {
+        (self.0).2.load(Ordering::Relaxed)
+    }
+
+    pub fn failover(&self) -> bool {
+        (self.0).3.load(Ordering::Relaxed)
+    }
+}
+
+// Room for transport messages waiting on a full beat channel.
+const HELD_MSGS: usize = 16;
+
+// Sends clock messages without blocking. A tick that doesn't fit is dropped
+// and counted as missed, which the next tick makes up for since it carries
+// the count. Start, Stop, Continue and positions wait here instead and go
+// out in order before anything sent later. Only if HELD_MSGS of them pile
+// up is one dropped, so that holding them never allocates.
+pub struct ClockSender {
+    beats: Sender<ClockMsg>,
+    missed: Arc<AtomicU64>,
+    held: VecDeque<ClockMsg>,
+}
+
+impl ClockSender {
+    pub fn new(beats: Sender<ClockMsg>, missed: Arc<AtomicU64>) -> Self {
+        ClockSender { beats: beats,
+                      missed: missed,
+                      held: VecDeque::with_capacity(HELD_MSGS) }
+    }
+
+    // Try the held messages again. False once the receiver is gone.
+    pub fn flush(&mut self) -> bool {
+        while let Some(msg) = self.held.front() {
+            match self.beats.try_send(*msg) {
+                Ok(_) => { self.held.pop_front(); },
+                Err(TrySendError::Full(_)) => break,
+                Err(TrySendError::Disconnected(_)) => return false,
+            }
+        }
+        true
+    }
+
+    // False once the receiver is gone.
+    pub fn send(&mut self, msg: ClockMsg) -> bool {
+        if !self.flush() {
+            return false;
+        }
+        if self.held.is_empty() {
+            match self.beats.try_send(msg) {
+                Ok(_) => return true,
+                Err(TrySendError::Full(_)) => {},
+                Err(TrySendError::Disconnected(_)) => return false,
+            }
+        }
+        match msg {
+            ClockMsg::Tick(_) => {},
+            _ if self.held.len() < HELD_MSGS => {
+                self.held.push_back(msg);
+                return true;
+            },
+            _ => {},
+        }
+        self.missed.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    // For threads that can wait, transport messages block until there's
+    // room instead of being held.
+    pub fn send_blocking(&mut self, msg: ClockMsg) -> bool {
+        match msg {
+            ClockMsg::Tick(_) => self.send(msg),
+            _ => self.flush() && self.beats.send(msg).is_ok(),
+        }
+    }
+}
+
+// Sends ticks from a thread of its own, with Start before the first one and
+// Stop and Continue as the tempo is stopped and started.
+pub fn spawn_clock(tempo: Tempo, beats: Sender<ClockMsg>,
+                   missed: Arc<AtomicU64>) {
+    thread::spawn(move || {
+        let mut out = ClockSender::new(beats, Arc::clone(&missed));
+        let mut next = Instant::now();
+        let mut tick = 0;
+        let mut last = None;
+        loop {
+            let running = tempo.running();
+            let msg = match (last, running) {
+                (None, true) => Some(ClockMsg::Start),
+                (Some(false), true) => Some(ClockMsg::Continue),
+                (Some(true), false) => Some(ClockMsg::Stop),
+                _ => None,
+            };
+            last = Some(running);
+            if let Some(msg) = msg {
+                if !out.send_blocking(msg) { break }
+            }
+            if !running {
+                thread::sleep(Duration::from_millis(10));
+                next = Instant::now();
+                continue;
+            }
+            let step = tempo.tick_secs();
+            next += Duration::from_secs_f64(step);
+            let now = Instant::now();
+            if next > now {
+                thread::sleep(next - now);
+            } else {
+                // Ticks that are already overdue are skipped rather than
+                // sent in a burst.
+                let late = ((now - next).as_secs_f64() / step).floor();
+                missed.fetch_add(late as u64, Ordering::Relaxed);
+                next += Duration::from_secs_f64(late * step);
+            }
+            tick += 1;
+            if !out.send_blocking(ClockMsg::Tick(tick)) { break }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_ticks() {
+        // 125 BPM at 48kHz is a tick every 960 frames.
+        let tempo = Tempo::new(125.0);
+        let mut clock = FrameClock::new(tempo.clone());
+        assert_eq!(clock.cycle(1024, 48000).collect::<Vec<_>>(), vec![0, 960]);
+        assert_eq!(clock.cycle(1024, 48000).collect::<Vec<_>>(), vec![896]);
+        assert_eq!(clock.cycle(512, 48000).count(), 0);
+
+        tempo.set(250.0);
+        assert_eq!(clock.cycle(1024, 48000).collect::<Vec<_>>(),
+                   vec![320, 800]);
+
+        tempo.set_running(false);
+        let ticks = clock.cycle(1024, 48000);
+        assert!(!ticks.running());
+        assert_eq!(ticks.count(), 0);
+        tempo.set_running(true);
+        assert_eq!(clock.cycle(1024, 48000).collect::<Vec<_>>(),
+                   vec![0, 480, 960]);
+    }
+
+    #[test]
+    fn transport_sync() {
+        // 960 frames a tick, in cycles of 1440 frames.
+        let mut sync = TransportSync::new();
+        let mut cycle = |rolling, pos| {
+            let mut msgs = Vec::new();
+            let ticks = sync.cycle(rolling, pos, 960.0, 1440,
+                                   |m| msgs.push(m));
+            (ticks.collect::<Vec<_>>(), msgs)
+        };
+        assert_eq!(cycle(false, 10.5),
+                   (vec![], vec![ClockMsg::Locate(11), ClockMsg::Stop]));
+        assert_eq!(cycle(true, 10.5), (vec![480], vec![ClockMsg::Continue]));
+        assert_eq!(cycle(true, 12.0), (vec![0, 960], vec![]));
+        assert_eq!(cycle(true, 96.25), (vec![720], vec![ClockMsg::Locate(97)]));
+        assert_eq!(cycle(true, 97.75), (vec![240, 1200], vec![]));
+    }
+
+    #[test]
+    fn clock_meter() {
+        // 125 BPM at 48kHz, with every other tick 48 frames late, across
+        // the frame time wrapping around.
+        let start = u32::MAX - 4000;
+        let mut meter = ClockMeter::new();
+        assert_eq!(meter.bpm(48000), None);
+        for i in 0..9u32 {
+            meter.tick(start.wrapping_add(i * 960 + (i % 2) * 48));
+        }
+        assert_eq!(meter.bpm(48000), Some(125.0));
+        assert_eq!(meter.jitter(48000), Some(1.0));
+
+        let last = start.wrapping_add(8 * 960);
+        assert_eq!(meter.due(last.wrapping_add(2400)), Some(2.5));
+        assert!(!meter.stalled(last.wrapping_add(960)));
+        assert!(meter.stalled(last.wrapping_add(25 * 960)));
+        meter.pause();
+        assert!(!meter.stalled(last.wrapping_add(25 * 960)));
+    }
+
+    #[test]
+    fn realign_fallback() {
+        // The fallback took over at beat 100 and has counted to 110.
+        // Returning late, the count jumps ahead to where it should be.
+        assert_eq!(realign(100, Some(14.4), 110), (113, 0));
+        assert_eq!(realign(100, Some(11.0), 110), (110, 0));
+        // Returning early, ticks the fallback already counted are skipped.
+        assert_eq!(realign(100, Some(10.0), 110), (110, 1));
+        assert_eq!(realign(100, Some(5.6), 110), (110, 5));
+        assert_eq!(realign(100, None, 110), (110, 10));
+    }
+
+    #[test]
+    fn place_ticks() {
+        let run = |ticks: &[f64], last, stopped, step| {
+            let mut at = Vec::new();
+            let (done, last) = place(ticks.iter().map(|&t| (t, ())), last,
+                                     stopped, step, 1024,
+                                     |t, _| at.push(t));
+            (at, done, last)
+        };
+        // Late messages go out at the start, and later ones never go
+        // before earlier ones.
+        assert_eq!(run(&[8.0, 9.5, 10.0, 10.25, 12.0], (10, 100.0), false,
+                       960.0),
+                   (vec![0, 0, 100, 340], 4, (10, -924.0)));
+        assert_eq!(run(&[10.5, 10.0], (10, 100.0), false, 960.0),
+                   (vec![580, 580], 2, (10, -924.0)));
+        // Stopped, everything is flushed.
+        assert_eq!(run(&[10.0, 50.0], (10, 100.0), true, 960.0),
+                   (vec![0, 0], 2, (10, -924.0)));
+        // With no tempo, only what's already due goes out.
+        assert_eq!(run(&[9.0, 11.0], (10, 100.0), false, 0.0),
+                   (vec![0], 1, (10, -924.0)));
+        // The last tick carries over into the next cycle.
+        assert_eq!(run(&[11.0], (10, 600.0), false, 960.0),
+                   (vec![], 0, (10, -424.0)));
+        assert_eq!(run(&[11.0], (10, -424.0), false, 960.0),
+                   (vec![536], 1, (10, -1448.0)));
+    }
+
+    #[test]
+    fn clock_sender() {
+        let (snd, rcv) = crossbeam_channel::bounded(1);
+        let missed = Arc::new(AtomicU64::new(0));
+        let mut out = ClockSender::new(snd, Arc::clone(&missed));
+        assert!(out.send(ClockMsg::Tick(1)));
+        assert!(out.send(ClockMsg::Tick(2)));
+        assert!(out.send(ClockMsg::Stop));
+        assert!(out.send(ClockMsg::Tick(3)));
+        assert_eq!(missed.load(Ordering::Relaxed), 2);
+
+        // Stop is held and goes out ahead of later ticks.
+        assert_eq!(rcv.try_recv(), Ok(ClockMsg::Tick(1)));
+        assert!(out.send(ClockMsg::Tick(4)));
+        assert_eq!(rcv.try_recv(), Ok(ClockMsg::Stop));
+        assert!(rcv.try_recv().is_err());
+        assert_eq!(missed.load(Ordering::Relaxed), 3);
+
+        drop(rcv);
+        assert!(!out.send(ClockMsg::Tick(5)));
+    }
+
+    #[test]
+    fn tap_tempo() {
+        let start = Instant::now();
+        let ms = |n| start + Duration::from_millis(n);
+        let mut taps = TapTempo::new();
+        assert_eq!(taps.tap(ms(0)), None);
+        assert_eq!(taps.tap(ms(500)), Some(120.0));
+        assert_eq!(taps.tap(ms(1100)), Some(60.0 * 2.0 / 1.1));
+        assert_eq!(taps.tap(ms(4000)), None);
+        assert_eq!(taps.tap(ms(4250)), Some(240.0));
+
+        let mut taps = TapTempo::new();
+        taps.tap(ms(0));
+        assert_eq!(taps.tap(ms(10)), Some(MAX_BPM));
+    }
+
+    #[test]
+    fn bpm_range() {
+        assert_eq!(clamp_bpm(120.0), Some(120.0));
+        assert_eq!(clamp_bpm(0.25), Some(MIN_BPM));
+        assert_eq!(clamp_bpm(1e9), Some(MAX_BPM));
+        assert_eq!(clamp_bpm(0.0), None);
+        assert_eq!(clamp_bpm(-5.0), None);
+        assert_eq!(clamp_bpm(f64::NAN), None);
+        assert_eq!(clamp_bpm(f64::INFINITY), None);
+    }
+}
@@ -18,10 +18,17 @@
 use arr_macro::arr;
 use config::{Config, ConfigError, File, Value};
 use std::collections::{HashSet, HashMap};
+use std::fs;
+use std::path::PathBuf;
 use std::rc::Rc;
 use std::str::FromStr;
 use log::*;
 
+use crate::api::ChannelInfo;
+use crate::befunge::CharMap;
+use crate::clock::clamp_bpm;
+use crate::midi_bridge::FilterSpec;
+
 pub struct ChannelConfig {
     pub local: Rc<str>,
     pub starting: u8,
@@ -31,6 +38,14 @@ pub struct ChannelConfig {
     pub note_filter: Option<String>,
 }
 
+impl ChannelConfig {
+    // The MIDI channel that channel ch plays on. Every backend goes through
+    // here, so ranges longer than 16 channels wrap the same way everywhere.
+    pub fn midi_channel(&self, ch: u8) -> u8 {
+        ch.wrapping_sub(self.starting) % 16
+    }
+}
+
 #[derive(Clone)]
 pub struct SubprocessCommand {
     pub name: String,
@@ -41,16 +56,52 @@ pub struct SubprocessCommand {
     pub reload: bool
 }
 
+// Which MIDI system funged takes its clock from and plays notes through.
+// Only jack measures the incoming clock, so the tempo, jitter and stall
+// reports and the fallback clock for a quiet beats_in are jack-only; alsa
+// passes its clock through unmeasured.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BackendKind {
+    Jack,
+    Alsa,
+    Null,
+}
+
+// Whether beats come from MIDI clock on beats_in, are generated here, or
+// follow the jack transport.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ClockSource {
+    External,
+    Internal,
+    Transport,
+}
+
 pub struct FungedConfig {
     pub host: String,
     pub port: u16,
-    pub beat_source: Rc<str>,
+    // The HTTP API listens on either this Unix socket or host:port, never
+    // both. host and port keep their defaults but go unused.
+    pub socket: Option<String>,
+    pub osc_host: String,
+    pub osc_port: Option<u16>,
+    // /process over OSC only reads programs under this directory.
+    pub osc_dir: PathBuf,
+    pub osc_out: Vec<String>,
+    pub charmap: CharMap,
+    pub read_token: Option<String>,
+    pub control_token: Option<String>,
+    pub backend: BackendKind,
+    pub clock: ClockSource,
+    pub beat_source: Option<Rc<str>>,
+    pub clock_out: Option<Rc<str>>,
     pub period: u64,
+    pub tempo: Option<f64>,
     pub locals: HashSet<Rc<str>>,
     pub connections: Vec<(Rc<str>, String)>,
     pub extra_connections: Vec<(String, String)>,
     pub channels: [Option<ChannelConfig>; 256],
     pub preload: Vec<String>,
+    pub outbound: Vec<u8>,
     pub subprocesses: Vec<SubprocessCommand>,
     pub log_level: LevelFilter
 }
@@ -106,6 +157,62 @@ fn get_preload(settings: &Config) -> Vec<String> {
     }).collect()
 }
 
+fn get_optional_str(settings: &Config, key: &str) -> Option<String> {
+    match settings.get_str(key) {
+        Ok(val) => Some(val),
+        Err(ConfigError::NotFound(_)) => None,
+        Err(e) => panic!("Bad {}: {:?}", key, e),
+    }
+}
+
+// The charmap can be given inline or in its own file, but not both.
+fn get_charmap(settings: &Config) -> CharMap {
+    let glyphs = match (get_optional_str(settings, "charmap"),
+                        get_optional_str(settings, "charmap_file")) {
+        (None, None) => return CharMap::default(),
+        (Some(glyphs), None) => glyphs,
+        (None, Some(file)) => fs::read_to_string(&file).expect(
+            &format!("Failed to open charmap file: {}", file)),
+        (Some(_), Some(_)) =>
+            panic!("Only one of charmap and charmap_file can be set"),
+    };
+    match CharMap::parse(&glyphs) {
+        Ok(cm) => cm,
+        Err(e) => panic!("Bad charmap: {}", e),
+    }
+}
+
+fn get_osc_out(settings: &Config) -> Vec<String> {
+    match settings.get_str("osc_out") {
+        Ok(val) => return vec![val],
+        Err(ConfigError::NotFound(_)) => return Vec::new(),
+        _ => {}
+    }
+
+    match settings.get_array("osc_out") {
+        Ok(vals) => vals,
+        Err(e) => panic!("Bad osc_out: {:?}", e),
+    }.into_iter().map(|v| {
+        match v.into_str() {
+            Ok(s) => s,
+            Err(e) => panic!("Bad osc_out: {:?}", e)
+        }
+    }).collect()
+}
+
+fn get_outbound(settings: &Config) -> Vec<u8> {
+    match settings.get_array("outbound") {
+        Ok(vals) => vals,
+        Err(ConfigError::NotFound(_)) => Vec::new(),
+        Err(e) => panic!("Bad outbound: {:?}", e),
+    }.into_iter().map(|v| {
+        match v.into_int() {
+            Ok(i) if i >= 0 && i <= 255 => i as u8,
+            _ => panic!("outbound channels must be integers from 0 to 255")
+        }
+    }).collect()
+}
+
 fn get_subprocesses(settings: &Config) -> Vec<SubprocessCommand> {
     let mut subs = Vec::new();
 
@@ -183,27 +290,115 @@ fn get_extra_connections(settings: &Config) -> Vec<(String, String)> {
 }
 
 impl FungedConfig {
+    pub fn channel_info(&self) -> Vec<ChannelInfo> {
+        self.channels.iter().enumerate().filter_map(|(i, ch)| {
+            let ch = ch.as_ref()?;
+            let spec = match &ch.note_filter {
+                Some(f) => FilterSpec::parse(f).ok(),
+                None => Some(FilterSpec::Basic),
+            };
+            Some(ChannelInfo {
+                channel: i as u8,
+                port: ch.local.to_string(),
+                midi_channel: ch.midi_channel(i as u8),
+                bank: ch.bank,
+                program: ch.program,
+                pan: ch.pan,
+                note_filter: ch.note_filter.clone(),
+                filter: spec.map(|s| s.info())
+            })
+        }).collect()
+    }
+
     pub fn read_config(file: &str) -> FungedConfig {
+        FungedConfig::read(file, false)
+    }
+
+    // For running programs without a MIDI backend. The file is read as if
+    // backend = "null", so settings only JACK or ALSA need aren't required.
+    pub fn read_offline(file: &str) -> FungedConfig {
+        FungedConfig::read(file, true)
+    }
+
+    fn read(file: &str, offline: bool) -> FungedConfig {
         let mut settings = Config::default();
 
         settings.set_default("host", "127.0.0.1").unwrap();
         settings.set_default("port", 1312).unwrap();
         settings.set_default("period", 24).unwrap();
         settings.set_default("log_level", "INFO").unwrap();
+        settings.set_default("backend", "jack").unwrap();
+        settings.set_default("osc_host", "127.0.0.1").unwrap();
+        settings.set_default("osc_dir", ".").unwrap();
 
         settings.merge(File::with_name(&file)).unwrap();
+        if offline {
+            settings.set("backend", "null").unwrap();
+        }
         let host = settings.get_str("host").unwrap();
         let port = settings.get_int("port").expect("Port not set") as u16;
-        let bi = settings.get_str("beats_in").expect("Beats in not found.");
+        let socket = get_optional_str(&settings, "socket");
+        let osc_port = match settings.get_int("osc_port") {
+            Ok(p) => Some(p as u16),
+            Err(ConfigError::NotFound(_)) => None,
+            Err(e) => panic!("Bad osc_port: {:?}", e),
+        };
+        let osc_host = settings.get_str("osc_host").unwrap();
+        let osc_dir = PathBuf::from(settings.get_str("osc_dir").unwrap());
+        let backend = match settings.get_str("backend").unwrap().as_str() {
+            "jack" => BackendKind::Jack,
+            "alsa" => BackendKind::Alsa,
+            "null" => BackendKind::Null,
+            b => panic!("Bad backend: {}", b),
+        };
+        // The null backend has nothing to listen to.
+        let clock = match get_optional_str(&settings, "clock").as_deref() {
+            _ if backend == BackendKind::Null => ClockSource::Internal,
+            None | Some("external") => ClockSource::External,
+            Some("internal") => ClockSource::Internal,
+            Some("transport") => ClockSource::Transport,
+            Some(c) => panic!("Bad clock: {}", c),
+        };
+        let bi = get_optional_str(&settings, "beats_in");
+        if bi.is_none() && clock == ClockSource::External {
+            panic!("Beats in not found.");
+        }
         let period = settings.get_int("period").unwrap();
         if 24 % period != 0 {
             panic!("Period must be one of: 1,2,3,4,6,8,12,24");
         }
 
+        let tempo = match settings.get_float("tempo") {
+            Ok(t) => match clamp_bpm(t) {
+                Some(t) => Some(t),
+                None => panic!("Bad tempo: {}", t),
+            },
+            Err(ConfigError::NotFound(_)) => None,
+            Err(e) => panic!("Bad tempo: {:?}", e),
+        };
+
         let mut locals = HashSet::new();
         let mut channels = arr![None; 256];
         let mut connections = Vec::new();
 
+        // A port that sends our clock to whatever should follow it.
+        let clock_out = match settings.get_table("clock_out") {
+            Ok(table) => {
+                if clock != ClockSource::Internal {
+                    panic!("clock_out needs clock = \"internal\"");
+                }
+                let name = table.get("name")
+                                .and_then(|v| v.clone().into_str().ok())
+                                .unwrap_or_else(|| "clock_out".to_string());
+                let name = Rc::from(name);
+                connections.extend_from_slice(
+                    &get_connections(&name, &table));
+                Some(name)
+            },
+            Err(ConfigError::NotFound(_)) => None,
+            Err(e) => panic!("Bad clock_out: {:?}", e),
+        };
+
         for t in settings.get_table("out").unwrap_or(HashMap::new()) {
             let (local, block) = t;
             let local = Rc::from(local);
@@ -254,6 +449,15 @@ impl FungedConfig {
 
         let preload = get_preload(&settings);
 
+        let outbound = get_outbound(&settings);
+
+        let osc_out = get_osc_out(&settings);
+
+        let charmap = get_charmap(&settings);
+
+        let read_token = get_optional_str(&settings, "read_token");
+        let control_token = get_optional_str(&settings, "control_token");
+
         let subs = get_subprocesses(&settings);
 
         let log_level = settings.get_str("log_level")
@@ -267,13 +471,26 @@ impl FungedConfig {
 
         FungedConfig { host: host,
                        port: port,
-                       beat_source: Rc::from(bi),
+                       socket: socket,
+                       osc_host: osc_host,
+                       osc_port: osc_port,
+                       osc_dir: osc_dir,
+                       osc_out: osc_out,
+                       charmap: charmap,
+                       read_token: read_token,
+                       control_token: control_token,
+                       backend: backend,
+                       clock: clock,
+                       beat_source: bi.map(Rc::from),
+                       clock_out: clock_out,
                        period: period as u64,
+                       tempo: tempo,
                        locals: locals,
                        connections: connections,
                        extra_connections: extra_connections,
                        channels: channels,
                        preload: preload,
+                       outbound: outbound,
                        subprocesses: subs,
                        log_level: log_level }
     }
@@ -20,8 +20,8 @@ use arr_macro::arr;
 use jack::*;
 use log::*;
 use std::cmp;
+use std::cell::RefCell;
 use std::collections::HashMap;
-use std::fmt;
 use std::mem;
 use std::rc::Rc;
 use std::sync::atomic::{AtomicU64, AtomicBool, Ordering};
@@ -30,25 +30,39 @@ use std::thread;
 use std::thread::{JoinHandle};
 use std::time::Duration;
 use crossbeam_channel::{bounded, Sender, Receiver};
+use serde::Serialize;
 
-use crate::config::{FungedConfig, ChannelConfig};
+use crate::backend::{Backend, BackendError, ClockMsg};
+use crate::clock::{ClockHealth, ClockSender, ClockMeter, FrameClock, Tempo,
+                   TransportSync, PPQN, place, realign};
+use crate::config::{ClockSource, FungedConfig, ChannelConfig};
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
 pub enum MidiMsg {
     On(u8, u8, u8),
     Off(u8, u8),
     Program(u8, Option<u16>, Option<u8>),
     Pan(u8, u8),
+    AllOff(u8),
 }
 
 unsafe impl Send for MidiMsg {}
 
+// A message and the clock tick, counted as in ClockMsg::Tick, it should go
+// out on. Fractional ticks fall between clock bytes, and anything already
+// due goes out straight away.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TimedMsg {
+    pub tick: f64,
+    pub msg: MidiMsg,
+}
+
 #[derive(Copy, Clone)]
 struct FakeWriter(u64);
 
 struct PortMap {
     ports: Box<[Port<MidiOut>]>,
-    mapping: [Option<(u8, usize)>; 256]
+    mapping: [Option<(u8, usize)>; 256] // MIDI channel and port
 }
 
 struct Writers<'a> {
@@ -58,8 +72,8 @@ struct Writers<'a> {
 
 impl<'a> Writers<'a> {
     fn get_writer(&mut self, ch: u8) -> Option<(u8, &mut MidiWriter<'a>)> {
-        let (st, i) = self.mapping[ch as usize]?;
-        self.writers[i].as_mut().map( |w| (ch - st, w) )
+        let (mc, i) = self.mapping[ch as usize]?;
+        self.writers[i].as_mut().map( |w| (mc, w) )
     }
 }
 
@@ -79,7 +93,7 @@ impl PortMap {
                 Some(cc) => cc
             };
             let vec_index = name_map.get(&cc.local).unwrap();
-            mapping[i] = Some((cc.starting, *vec_index));
+            mapping[i] = Some((cc.midi_channel(i as u8), *vec_index));
         }
         PortMap {
             ports: port_vec.into_boxed_slice(),
@@ -131,55 +145,313 @@ impl ConnectHandle {
     }
 }
 
-#[derive(Copy, Clone, Debug)]
-pub enum JackError {
-    UnknownChannel(u8),
-    WriteFailed,
+
+pub struct JackHandle {
+    pub beat_channel: Receiver<ClockMsg>,
+    pub err_channel: Receiver<BackendError>,
+    missed_beats: Arc<AtomicU64>,
+    note_channel: Sender<TimedMsg>,
+    connect_handle: RefCell<ConnectHandle>,
+    deactivate: Box<dyn FnOnce()>,
+    tempo: Option<Tempo>,
+    health: ClockHealth,
+}
+
+// Messages that can wait in the process callback for their tick.
+const PENDING_MSGS: usize = 1024;
+
+// Order messages by the tick they're due at, and those due together by
+// the order they were sent in.
+fn sort_pending(pending: &mut [(u64, TimedMsg)]) {
+    pending.sort_unstable_by(|(s1, m1), (s2, m2)| {
+        m1.tick.partial_cmp(&m2.tick).unwrap_or(cmp::Ordering::Equal)
+               .then(s1.cmp(s2))
+    });
+}
+
+// The part of the process callback that talks to the main thread.
+struct Link {
+    clock: ClockSender,
+    err_channel: Sender<BackendError>,
+    note_channel: Receiver<TimedMsg>,
+    beat: u64,
+    stopped: bool,
+    last: (u64, f64), // Beat and frame of the last tick in this cycle
+    pending: Vec<(u64, TimedMsg)>, // Kept in the order they arrived
+    seq: u64,
+    full: bool, // Whether pending was left full last cycle
 }
 
-impl fmt::Display for JackError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            JackError::UnknownChannel(ch) =>
-                write!(f, "unknown channel: {}", ch),
-            JackError::WriteFailed =>
-                write!(f, "write failed"),
+impl Link {
+    fn send(&mut self, msg: ClockMsg) {
+        if !self.clock.send(msg) {
+            panic!("try_send failed: disconnected");
+        }
+        match msg {
+            ClockMsg::Stop => self.stopped = true,
+            ClockMsg::Start | ClockMsg::Continue => self.stopped = false,
+            _ => {},
+        }
+    }
+
+    // Count a clock tick at frame t.
+    fn tick(&mut self, t: u32) {
+        self.beat += 1;
+        self.last = (self.beat, t as f64);
+        self.send(ClockMsg::Tick(self.beat));
+    }
+
+    // Write out whatever falls due in a cycle of the given length, placing
+    // it from the last tick and the frames per tick. With nothing ticking,
+    // everything goes out at once.
+    fn schedule(&mut self, wtrs: &mut Writers, frames: u32, step: f64) {
+        // Past capacity, messages wait in note_channel until there's room.
+        while self.pending.len() < PENDING_MSGS {
+            match self.note_channel.try_recv() {
+                Ok(msg) => {
+                    self.seq += 1;
+                    self.pending.push((self.seq, msg));
+                },
+                Err(_) => break,
+            }
+        }
+        let full = self.pending.len() == PENDING_MSGS
+                   && !self.note_channel.is_empty();
+        if full && !self.full {
+            let _ = self.err_channel.try_send(BackendError::QueueFull);
+        }
+        self.full = full;
+        sort_pending(&mut self.pending);
+
+        let msgs = self.pending.iter().map(|(_, m)| (m.tick, m.msg));
+        let (done, last) = place(msgs, self.last, self.stopped, step, frames,
+                                 |t, msg| self.emit(wtrs, t, msg));
+        self.pending.drain(..done);
+        self.last = last;
+    }
+
+    fn emit(&self, wtrs: &mut Writers, t: u32, msg: MidiMsg) {
+        match msg {
+            MidiMsg::On(ch, pch, vel) => {
+                let (ch, wtr) = match wtrs.get_writer(ch) {
+                    Some(tup) => tup,
+                    None => {
+                        self.err_channel.try_send(
+                            BackendError::UnknownChannel(ch))
+                            .expect("failed to write error");
+                        return;
+                    },
+                };
+                if wtr.write(
+                    &jack::RawMidi {
+                        time: t,
+                        bytes: &[144 + ch, pch, vel]
+                    }).is_err() {
+                        self.err_channel.try_send(
+                            BackendError::WriteFailed)
+                            .expect("failed to write error.");
+                }
+            },
+            MidiMsg::Off(ch, pch) => {
+                let (ch, wtr) = match wtrs.get_writer(ch) {
+                    Some(tup) => tup,
+                    None => {
+                        self.err_channel.try_send(
+                            BackendError::UnknownChannel(ch))
+                            .expect("failed to write error");
+                        return;
+                    },
+                };
+                if wtr.write(
+                    &jack::RawMidi {
+                        time: t,
+                        bytes: &[128 + ch, pch, 0]
+                    }).is_err() {
+                        self.err_channel.try_send(
+                            BackendError::WriteFailed)
+                        .expect("failed to write error");
+                }
+            },
+            MidiMsg::Program(ch, bank, patch) => {
+                let (ch, wtr) = match wtrs.get_writer(ch) {
+                    Some(tup) => tup,
+                    None => {
+                        self.err_channel.try_send(
+                            BackendError::UnknownChannel(ch))
+                            .expect("failed to write error");
+                        return;
+                    },
+                };
+                if let Some(bank) = bank {
+                    if wtr.write(
+                        &jack::RawMidi {
+                            time: t,
+                            bytes: &[176 + ch, 0, (bank >> 7) as u8]
+                        }).is_err() {
+                        self.err_channel.try_send(
+                            BackendError::WriteFailed)
+                        .expect("failed to write error");
+                    }
+                    if wtr.write(
+                        &jack::RawMidi {
+                            time: t,
+                            bytes: &[176 + ch, 32,
+                                     (bank & 127) as u8]
+                        }).is_err() {
+                        self.err_channel.try_send(
+                            BackendError::WriteFailed)
+                        .expect("failed to write error");
+                    }
+                }
+                if let Some(patch) = patch {
+                    if wtr.write(
+                        &jack::RawMidi {
+                            time: t,
+                            bytes: &[192 + ch, patch]
+                        }).is_err() {
+                        self.err_channel.try_send(
+                            BackendError::WriteFailed)
+                        .expect("failed to write error");
+                    }
+                }
+            },
+            MidiMsg::Pan(ch, pan) => {
+                let (ch, wtr) = match wtrs.get_writer(ch) {
+                    Some(tup) => tup,
+                    None => {
+                        self.err_channel.try_send(
+                            BackendError::UnknownChannel(ch))
+                            .expect("failed to write error");
+                        return;
+                    },
+                };
+                if wtr.write(
+                    &jack::RawMidi {
+                        time: t,
+                        bytes: &[176 + ch, 10,
+                                 (pan & 127) as u8]
+                    }).is_err() {
+                    self.err_channel.try_send(
+                        BackendError::WriteFailed)
+                    .expect("failed to write error");
+                }
+            },
+            MidiMsg::AllOff(ch) => {
+                let (ch, wtr) = match wtrs.get_writer(ch) {
+                    Some(tup) => tup,
+                    None => {
+                        self.err_channel.try_send(
+                            BackendError::UnknownChannel(ch))
+                            .expect("failed to write error");
+                        return;
+                    },
+                };
+                self.write(wtr, t, &[176 + ch, 123, 0]);
+            },
         }
     }
 }
 
-pub struct JackHandle {
-    pub beat_channel: Receiver<u64>,
-    pub err_channel: Receiver<JackError>,
-    missed_beats: Arc<AtomicU64>,
-    note_channel: Sender<MidiMsg>,
-    connect_handle: Option<ConnectHandle>,
-    deactivate: Box<dyn FnOnce()>,
+impl Link {
+    fn write(&self, wtr: &mut MidiWriter, t: u32, bytes: &[u8]) {
+        if wtr.write(&jack::RawMidi { time: t, bytes: bytes }).is_err() {
+            self.err_channel.try_send(BackendError::WriteFailed)
+                .expect("failed to write error");
+        }
+    }
+}
+
+// What the followers of clock_out were last told.
+#[derive(Copy, Clone, PartialEq)]
+enum Transport {
+    Idle,
+    Running,
+    Stopped,
+}
+
+// Ticks that can go missing from beats_in before the fallback clock takes
+// over.
+const FAILOVER_TICKS: f64 = 3.0;
+
+// Stands in for the external clock while it's missing. lost_at is the
+// beat of the last external tick.
+struct Fallback {
+    clock: FrameClock,
+    lost_at: u64,
+}
+
+// Where the ticks come from. Following the transport needs a tempo for when
+// there's no timebase master to give one.
+enum Source {
+    Internal(FrameClock),
+    Midi,
+    Transport(TransportSync, f64),
+}
+
+// Song position in ticks, and frames per tick, from the timebase master if
+// there is one and otherwise from the frame at the given tempo.
+fn song_position(pos: &TransportPosition, rate: f64, tempo: f64)
+    -> (f64, f64) {
+    match pos.bbt() {
+        Some(bbt) => {
+            let quarters = 4.0 / bbt.sig_denom as f64;
+            let beats = (bbt.bar - 1) as f64 * bbt.sig_num as f64
+                        + (bbt.beat - 1) as f64
+                        + bbt.tick as f64 / bbt.ticks_per_beat;
+            (beats * quarters * PPQN,
+             60.0 * rate / (bbt.bpm * quarters * PPQN))
+        },
+        None => {
+            let step = 60.0 * rate / (tempo * PPQN);
+            (pos.frame() as f64 / step, step)
+        },
+    }
 }
 
 struct Handler {
-    beat_channel: Sender<u64>,
-    err_channel: Sender<JackError>,
-    note_channel: Receiver<MidiMsg>,
-    missed_beats: Arc<AtomicU64>,
+    link: Link,
     ports: PortMap,
     beats_in: Port<MidiIn>,
-    beat: u64,
+    clock: Source,
+    clock_out: Option<Port<MidiOut>>,
+    transport: Transport,
+    meter: ClockMeter,
+    health: ClockHealth,
+    fallback: Option<Fallback>,
+    fallback_tempo: Tempo, // Made up front, so failing over doesn't allocate
+    ahead: u64, // Fallback ticks the external clock has yet to catch up on
 }
 
 impl Handler {
-    fn new(beat_channel: Sender<u64>, err_channel: Sender<JackError>,
-           note_channel: Receiver<MidiMsg>, missed_beats: Arc<AtomicU64>,
-           ports: PortMap, beats_in: Port<MidiIn>) -> Handler {
+    fn new(beat_channel: Sender<ClockMsg>,
+           err_channel: Sender<BackendError>,
+           note_channel: Receiver<TimedMsg>, missed_beats: Arc<AtomicU64>,
+           ports: PortMap, beats_in: Port<MidiIn>,
+           clock: Source, clock_out: Option<Port<MidiOut>>,
+           health: ClockHealth) -> Handler {
 
         Handler {
-            beat_channel: beat_channel,
-            err_channel: err_channel,
-            note_channel: note_channel,
-            missed_beats: missed_beats,
+            link: Link {
+                clock: ClockSender::new(beat_channel, missed_beats),
+                err_channel: err_channel,
+                note_channel: note_channel,
+                beat: 0,
+                stopped: false,
+                last: (0, 0.0),
+                pending: Vec::with_capacity(PENDING_MSGS),
+                seq: 0,
+                full: false
+            },
             ports: ports,
             beats_in: beats_in,
-            beat: 0
+            clock: clock,
+            clock_out: clock_out,
+            transport: Transport::Idle,
+            meter: ClockMeter::new(),
+            health: health,
+            fallback: None,
+            fallback_tempo: Tempo::new(120.0),
+            ahead: 0
         }
     }
 }
@@ -187,129 +459,141 @@ impl Handler {
 unsafe impl Send for Handler {}
 
 impl ProcessHandler for Handler {
-    fn process(&mut self, _cl: &Client, ps: &ProcessScope) -> Control {
+    fn process(&mut self, cl: &Client, ps: &ProcessScope) -> Control {
         let mut wtrs = self.ports.writers(ps);
+        let link = &mut self.link;
+        let meter = &mut self.meter;
+        let now = ps.last_frame_time();
+        if !link.clock.flush() {
+            panic!("try_send failed: disconnected");
+        }
 
-        for bin in self.beats_in.iter(ps) {
-            if bin.bytes[0] == 248 {
-                let t = bin.time;
-                self.beat += 1;
-                match self.beat_channel.try_send(self.beat) {
-                    Ok(_) => (),
-                    Err(e) if e.is_full() => {
-                        self.missed_beats.fetch_add(1, Ordering::Relaxed); },
-                    _ => panic!("try_send failed: disconnected")
+        match &mut self.clock {
+            Source::Internal(clock) => {
+                let ticks = clock.cycle(ps.n_frames(), cl.sample_rate());
+                let mut out = self.clock_out.as_mut().map(|p| p.writer(ps));
+                if !ticks.running() && self.transport == Transport::Running {
+                    self.transport = Transport::Stopped;
+                    meter.pause();
+                    link.send(ClockMsg::Stop);
+                    if let Some(out) = &mut out {
+                        link.write(out, 0, &[0xfc]);
+                    }
                 }
-                for msg in self.note_channel.try_iter() {
-                    match msg {
-                        MidiMsg::On(ch, pch, vel) => {
-                            let (ch, wtr) = match wtrs.get_writer(ch) {
-                                Some(tup) => tup,
-                                None => {
-                                    self.err_channel.try_send(
-                                        JackError::UnknownChannel(ch))
-                                        .expect("failed to write error");
-                                    continue;
-                                },
-                            };
-                            if wtr.write(
-                                &jack::RawMidi {
-                                    time: t,
-                                    bytes: &[144 + ch, pch, vel]
-                                }).is_err() {
-                                    self.err_channel.try_send(
-                                        JackError::WriteFailed)
-                                        .expect("failed to write error.");
+                for t in ticks {
+                    let change = match self.transport {
+                        Transport::Idle => Some((ClockMsg::Start, 0xfa)),
+                        Transport::Stopped => Some((ClockMsg::Continue, 0xfb)),
+                        Transport::Running => None,
+                    };
+                    if let Some((msg, byte)) = change {
+                        link.send(msg);
+                        if let Some(out) = &mut out {
+                            link.write(out, t, &[byte]);
+                        }
+                    }
+                    if let Some(out) = &mut out {
+                        link.write(out, t, &[0xf8]);
+                    }
+                    self.transport = Transport::Running;
+                    meter.tick(now.wrapping_add(t));
+                    link.tick(t);
+                }
+            },
+            Source::Midi => {
+                for bin in self.beats_in.iter(ps) {
+                    match bin.bytes {
+                        [0xf8, ..] => {
+                            let frame = now.wrapping_add(bin.time);
+                            if let Some(fb) = self.fallback.take() {
+                                // Pick up the count where the external
+                                // clock would have it by now.
+                                let (beat, ahead) = realign(
+                                    fb.lost_at, meter.due(frame), link.beat);
+                                link.beat = beat;
+                                self.ahead = ahead;
+                                meter.pause();
                             }
-                        },
-                        MidiMsg::Off(ch, pch) => {
-                            let (ch, wtr) = match wtrs.get_writer(ch) {
-                                Some(tup) => tup,
-                                None => {
-                                    self.err_channel.try_send(
-                                        JackError::UnknownChannel(ch))
-                                        .expect("failed to write error");
-                                    continue;
-                                },
-                            };
-                            if wtr.write(
-                                &jack::RawMidi {
-                                    time: t,
-                                    bytes: &[128 + ch, pch, 0]
-                                }).is_err() {
-                                    self.err_channel.try_send(
-                                        JackError::WriteFailed)
-                                    .expect("failed to write error");
+                            meter.tick(frame);
+                            if self.ahead > 0 {
+                                self.ahead -= 1;
+                            } else {
+                                link.tick(bin.time);
                             }
                         },
-                        MidiMsg::Program(ch, bank, patch) => {
-                            let (ch, wtr) = match wtrs.get_writer(ch) {
-                                Some(tup) => tup,
-                                None => {
-                                    self.err_channel.try_send(
-                                        JackError::UnknownChannel(ch))
-                                        .expect("failed to write error");
-                                    continue;
-                                },
-                            };
-                            if let Some(bank) = bank {
-                                if wtr.write(
-                                    &jack::RawMidi {
-                                        time: t,
-                                        bytes: &[176 + ch, 0, (bank >> 7) as u8]
-                                    }).is_err() {
-                                    self.err_channel.try_send(
-                                        JackError::WriteFailed)
-                                    .expect("failed to write error");
-                                }
-                                if wtr.write(
-                                    &jack::RawMidi {
-                                        time: t,
-                                        bytes: &[176 + ch, 32,
-                                                 (bank & 127) as u8]
-                                    }).is_err() {
-                                    self.err_channel.try_send(
-                                        JackError::WriteFailed)
-                                    .expect("failed to write error");
-                                }
-                            }
-                            if let Some(patch) = patch {
-                                if wtr.write(
-                                    &jack::RawMidi {
-                                        time: t,
-                                        bytes: &[192 + ch, patch]
-                                    }).is_err() {
-                                    self.err_channel.try_send(
-                                        JackError::WriteFailed)
-                                    .expect("failed to write error");
-                                }
-                            }
+                        [b @ 0xfa..=0xfc, ..] => {
+                            // The clock starts over from a transport change.
+                            self.fallback = None;
+                            self.ahead = 0;
+                            meter.pause();
+                            link.send(match b {
+                                0xfa => ClockMsg::Start,
+                                0xfb => ClockMsg::Continue,
+                                _ => ClockMsg::Stop,
+                            });
                         },
-                        MidiMsg::Pan(ch, pan) => {
-                            let (ch, wtr) = match wtrs.get_writer(ch) {
-                                Some(tup) => tup,
-                                None => {
-                                    self.err_channel.try_send(
-                                        JackError::UnknownChannel(ch))
-                                        .expect("failed to write error");
-                                    continue;
-                                },
-                            };
-                            if wtr.write(
-                                &jack::RawMidi {
-                                    time: t,
-                                    bytes: &[176 + ch, 10,
-                                             (pan & 127) as u8]
-                                }).is_err() {
-                                self.err_channel.try_send(
-                                    JackError::WriteFailed)
-                                .expect("failed to write error");
-                            }
+                        [0xf2, lsb, msb, ..] => {
+                            let pos = (*lsb as u64) | (*msb as u64) << 7;
+                            link.send(ClockMsg::Position(pos));
                         },
+                        _ => {},
                     }
                 }
-            }
+
+                if let Some(fb) = &mut self.fallback {
+                    for t in fb.clock.cycle(ps.n_frames(), cl.sample_rate()) {
+                        link.tick(t);
+                    }
+                } else if !link.stopped {
+                    // Carry on at the last measured tempo, in step with
+                    // the ticks that went missing.
+                    let end = now.wrapping_add(ps.n_frames());
+                    match (meter.due(end), meter.interval(),
+                           meter.bpm(cl.sample_rate())) {
+                        (Some(due), Some(interval), Some(bpm))
+                            if due > FAILOVER_TICKS => {
+                            let missing = due.floor();
+                            let next = (missing + 1.0 - due) * interval;
+                            self.fallback_tempo.set(bpm);
+                            self.fallback = Some(Fallback {
+                                clock: FrameClock::starting_at(
+                                           self.fallback_tempo.clone(), next),
+                                lost_at: link.beat
+                            });
+                            link.beat += missing as u64;
+                        },
+                        _ => {},
+                    }
+                }
+            },
+            Source::Transport(sync, tempo) => {
+                match cl.transport().query() {
+                    Ok(tsp) => {
+                        let (pos, step) = song_position(
+                            &tsp.pos, cl.sample_rate() as f64, *tempo);
+                        let rolling = tsp.state == TransportState::Rolling;
+                        let ticks = sync.cycle(rolling, pos, step,
+                                               ps.n_frames(),
+                                               |msg| link.send(msg));
+                        if !ticks.running() {
+                            meter.pause();
+                        }
+                        for t in ticks {
+                            meter.tick(now.wrapping_add(t));
+                            link.tick(t);
+                        }
+                    },
+                    // No ticks without a reading, but whatever is already
+                    // due still goes out.
+                    Err(_) => meter.pause(),
+                }
+            },
         }
+        link.schedule(&mut wtrs, ps.n_frames(),
+                      meter.interval().unwrap_or(0.0));
+        self.health.publish(meter, cl.sample_rate(),
+                            now.wrapping_add(ps.n_frames()),
+                            self.fallback.is_some());
         Control::Continue
     }
 }
@@ -328,6 +612,13 @@ impl JackHandle {
         let mut locals2 = HashMap::new();
         let missed = Arc::new(AtomicU64::new(0));
 
+        let clock_out = conf.clock_out.as_ref().map(|name| {
+            let port = client.register_port(name, MidiOut::default())
+                             .expect("Failed to register port");
+            locals2.insert(name.clone(), port.clone_unowned());
+            port
+        });
+
         for name in &conf.locals {
             let port = client.register_port(name, MidiOut::default())
                              .expect("Failed to register port");
@@ -338,12 +629,27 @@ impl JackHandle {
         //let mut portmap = PortMap::new(&conf.channels, locals);
 
         let (snd1, rcv1) = bounded(128);
-        let (snd2, rcv2) = bounded(4);
+        let (snd2, rcv2) = bounded(16);
         let (snd3, rcv3) = bounded(128);
 
+        let tempo = match conf.clock {
+            ClockSource::Internal =>
+                Some(Tempo::new(conf.tempo.unwrap_or(120.0))),
+            ClockSource::External | ClockSource::Transport => None,
+        };
+        let source = match &tempo {
+            Some(tempo) => Source::Internal(FrameClock::new(tempo.clone())),
+            None if conf.clock == ClockSource::Transport =>
+                Source::Transport(TransportSync::new(),
+                                  conf.tempo.unwrap_or(120.0)),
+            None => Source::Midi,
+        };
+        let health = ClockHealth::new();
         let handler = Handler::new(snd2, snd3, rcv1, missed.clone(),
                                    PortMap::new(&conf.channels, locals),
-                                   beats_in);
+                                   beats_in,
+                                   source,
+                                   clock_out, health.clone());
         let active = client.activate_async((),handler)
                            .expect("Failed to activate client.");
 
@@ -353,7 +659,12 @@ impl JackHandle {
             let src_name = &locals2.get(src).unwrap().name().unwrap();
             connections.push((String::from(src_name), String::from(dst)));
         }
-        connections.push((conf.beat_source.to_string(), String::from(bi_name)));
+        if conf.clock == ClockSource::External {
+            let beat_source = conf.beat_source.as_ref()
+                                  .expect("The jack backend needs beats_in");
+            connections.push((beat_source.to_string(),
+                              String::from(bi_name)));
+        }
         connections.extend(conf.extra_connections.clone());
 
         let instr_snd = snd1.clone();
@@ -400,7 +711,9 @@ impl JackHandle {
 
                 if new_conn {
                     for m in &instrs {
-                        instr_snd.send(*m).expect("Sender::send failed");
+                        let msg = TimedMsg { tick: f64::NEG_INFINITY,
+                                             msg: *m };
+                        instr_snd.send(msg).expect("Sender::send failed");
                     }
                 }
 
@@ -421,9 +734,11 @@ impl JackHandle {
                      err_channel: rcv3,
                      missed_beats: missed,
                      note_channel: snd1,
-                     connect_handle: Some(
+                     connect_handle: RefCell::new(
                         ConnectHandle::new(connect_done, connect_thread)),
-                     deactivate: deact }
+                     deactivate: deact,
+                     tempo: tempo,
+                     health: health }
     }
 
     pub fn missed(&self) -> u64 {
@@ -431,12 +746,11 @@ impl JackHandle {
     }
 
     pub fn send_midi(&self, msg: MidiMsg) -> bool {
-        self.note_channel.try_send(msg).is_ok()
+        self.send_midi_at(f64::NEG_INFINITY, msg)
     }
 
-    pub fn take_connect_handle(&mut self) -> ConnectHandle {
-        self.connect_handle.take()
-                           .expect("take_connect_handle already called.")
+    pub fn send_midi_at(&self, tick: f64, msg: MidiMsg) -> bool {
+        self.note_channel.try_send(TimedMsg { tick: tick, msg: msg }).is_ok()
     }
 
     pub fn shutdown(self) {
@@ -444,3 +758,52 @@ impl JackHandle {
     }
 
 }
+
+impl Backend for JackHandle {
+    fn beats(&self) -> &Receiver<ClockMsg> {
+        &self.beat_channel
+    }
+
+    fn errors(&self) -> &Receiver<BackendError> {
+        &self.err_channel
+    }
+
+    fn missed(&self) -> u64 {
+        JackHandle::missed(self)
+    }
+
+    fn maintain(&self) {
+        self.connect_handle.borrow_mut().join();
+    }
+
+    fn tempo(&self) -> Option<Tempo> {
+        self.tempo.clone()
+    }
+
+    fn health(&self) -> Option<ClockHealth> {
+        Some(self.health.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stop_after_notes() {
+        // The last step's note and the all notes off from stopping are both
+        // due at tick 10, and the clock stops before either goes out.
+        let timed = |tick, msg| TimedMsg { tick: tick, msg: msg };
+        let mut pending = vec![(1, timed(10.0, MidiMsg::On(0, 60, 100))),
+                               (2, timed(10.0, MidiMsg::AllOff(0))),
+                               (3, timed(f64::NEG_INFINITY,
+                                         MidiMsg::Pan(0, 64)))];
+        sort_pending(&mut pending);
+        let mut out = Vec::new();
+        let msgs = pending.iter().map(|(_, m)| (m.tick, m.msg));
+        place(msgs, (8, 0.0), true, 960.0, 1024, |t, msg| out.push((t, msg)));
+        assert_eq!(out, vec![(0, MidiMsg::Pan(0, 64)),
+                             (0, MidiMsg::On(0, 60, 100)),
+                             (0, MidiMsg::AllOff(0))]);
+    }
+}
@@ -21,6 +21,12 @@ pub mod jack;
 pub mod config;
 pub mod server;
 pub mod api;
+pub mod backend;
+pub mod clock;
+#[cfg(feature = "alsa")]
+pub mod alsa_seq;
 pub mod midi_bridge;
+pub mod osc;
+pub mod smf;
 pub mod subprocess;
 
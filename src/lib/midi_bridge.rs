@@ -18,13 +18,72 @@
 use arr_macro::arr;
 use log::*;
 use rand::Rng;
-use std::collections::{BTreeMap, VecDeque};
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::cell::RefCell;
 use std::mem;
 use std::rc::Rc;
-use crate::config::{FungedConfig};
+use crate::api::FilterInfo;
+use crate::config::{ChannelConfig, FungedConfig};
 use crate::befunge::{EventLog, Note};
 use crate::jack::{JackHandle, MidiMsg};
 
+// Somewhere for filters to send their output. Messages are stamped with the
+// engine beat they belong to. Sinks that can place messages between clock
+// ticks also take the tick, counted as in ClockMsg::Tick, they're due at.
+pub trait MidiSink {
+    fn send_midi(&self, beat: u64, msg: MidiMsg) -> bool;
+
+    fn send_midi_at(&self, beat: u64, _tick: f64, msg: MidiMsg) -> bool {
+        self.send_midi(beat, msg)
+    }
+}
+
+// JACK plays untimed messages as soon as it can.
+impl MidiSink for JackHandle {
+    fn send_midi(&self, _beat: u64, msg: MidiMsg) -> bool {
+        JackHandle::send_midi(self, msg)
+    }
+
+    fn send_midi_at(&self, _beat: u64, tick: f64, msg: MidiMsg) -> bool {
+        JackHandle::send_midi_at(self, tick, msg)
+    }
+}
+
+// Passes messages on stamped with a tick.
+struct AtTick<'a, S: MidiSink> {
+    sink: &'a S,
+    tick: f64,
+}
+
+impl<'a, S: MidiSink> MidiSink for AtTick<'a, S> {
+    fn send_midi(&self, beat: u64, msg: MidiMsg) -> bool {
+        self.sink.send_midi_at(beat, self.tick, msg)
+    }
+}
+
+// Keeps everything it is sent, for tests and offline runs.
+#[derive(Default)]
+pub struct RecordingSink(RefCell<Vec<(u64, MidiMsg)>>);
+
+impl RecordingSink {
+    pub fn new() -> Self {
+        RecordingSink(RefCell::new(Vec::new()))
+    }
+
+    // Remove and return everything recorded so far.
+    pub fn take(&self) -> Vec<(u64, MidiMsg)> {
+        mem::take(&mut *self.0.borrow_mut())
+    }
+}
+
+impl MidiSink for RecordingSink {
+    fn send_midi(&self, beat: u64, msg: MidiMsg) -> bool {
+        self.0.borrow_mut().push((beat, msg));
+        true
+    }
+}
+
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum Dir {
     Up,
@@ -59,7 +118,7 @@ fn parse_durs(v: Vec<&str>) -> Result<Rc<[u64]>, String> {
 }
 
 impl FilterSpec {
-    fn parse(input: &str) -> Result<Self, String> {
+    pub fn parse(input: &str) -> Result<Self, String> {
         let v : Vec<&str> = input.split(':').collect();
 
         if v.len() == 0 {
@@ -96,6 +155,19 @@ impl FilterSpec {
         Ok(FilterSpec::Arp(dir, parse_durs(v)?))
     }
 
+    pub fn info(&self) -> FilterInfo {
+        let (kind, durs) : (&str, &[u64]) = match self {
+            FilterSpec::Basic => ("basic", &[]),
+            FilterSpec::Solo => ("solo", &[]),
+            FilterSpec::RandomArp(durs) => ("random", durs),
+            FilterSpec::Arp(Dir::Up, durs) => ("up", durs),
+            FilterSpec::Arp(Dir::Down, durs) => ("down", durs),
+            FilterSpec::Arp(Dir::Bi, durs) => ("bi", durs),
+            FilterSpec::Pause(dur) => ("pause", std::slice::from_ref(dur)),
+        };
+        FilterInfo { kind: kind.to_string(), durations: durs.to_vec() }
+    }
+
     fn to_filter(&self, channel: u8) -> Box<dyn Filter> {
         match self {
             FilterSpec::Basic => Box::new(Basic::new(channel)),
@@ -111,9 +183,9 @@ impl FilterSpec {
 }
 
 pub trait Filter {
-    fn activate(&mut self, beat: u64, handle: &JackHandle);
-    fn push(&mut self, note: &Note, handle: &JackHandle);
-    fn resolve(&mut self, handle: &JackHandle) -> bool;
+    fn activate(&mut self, beat: u64, handle: &dyn MidiSink);
+    fn push(&mut self, note: &Note, handle: &dyn MidiSink);
+    fn resolve(&mut self, handle: &dyn MidiSink) -> bool;
 }
 
 // Basic - prevents a note from playing if it is already playing.
@@ -136,7 +208,7 @@ impl Basic {
 }
 
 impl Filter for Basic {
-    fn activate(&mut self, beat: u64, handle: &JackHandle) {
+    fn activate(&mut self, beat: u64, handle: &dyn MidiSink) {
         if self.current.is_some() {
             panic!("Basic::activate called twice");
         }
@@ -145,12 +217,12 @@ impl Filter for Basic {
         if let Some(evs) = self.off_events.remove(&beat) {
             for pch in evs {
                 self.active[pch as usize] = false;
-                handle.send_midi(MidiMsg::Off(self.channel, pch));
+                handle.send_midi(beat, MidiMsg::Off(self.channel, pch));
             }
         }
     }
 
-    fn push(&mut self, note: &Note, handle: &JackHandle) {
+    fn push(&mut self, note: &Note, handle: &dyn MidiSink) {
         let beat = self.current.expect("Basic::push without activate");
         let i = note.pch as usize;
         if i >= 127 { return }
@@ -161,10 +233,10 @@ impl Filter for Basic {
                        .or_insert_with(|| Vec::new())
                        .push(note.pch);
 
-        handle.send_midi(MidiMsg::On(note.cha, note.pch, note.vel));
+        handle.send_midi(beat, MidiMsg::On(note.cha, note.pch, note.vel));
     }
 
-    fn resolve(&mut self, _handle: &JackHandle) -> bool {
+    fn resolve(&mut self, _handle: &dyn MidiSink) -> bool {
         self.current = None;
         !self.off_events.is_empty()
     }
@@ -193,7 +265,7 @@ impl Pause {
 }
 
 impl Filter for Pause {
-    fn activate(&mut self, beat: u64, handle: &JackHandle) {
+    fn activate(&mut self, beat: u64, handle: &dyn MidiSink) {
         if self.current.is_some() {
             panic!("Basic::activate called twice");
         }
@@ -201,7 +273,7 @@ impl Filter for Pause {
 
         if let Some(evs) = self.off_events.remove(&beat) {
             for pch in evs {
-                handle.send_midi(MidiMsg::Off(self.channel, pch));
+                handle.send_midi(beat, MidiMsg::Off(self.channel, pch));
             }
         }
 
@@ -219,7 +291,7 @@ impl Filter for Pause {
         }
     }
 
-    fn push(&mut self, note: &Note, handle: &JackHandle) {
+    fn push(&mut self, note: &Note, handle: &dyn MidiSink) {
         let beat = self.current.expect("Basic::push without activate");
         let i = note.pch as usize;
         if i >= 127 { return }
@@ -230,10 +302,10 @@ impl Filter for Pause {
                        .or_insert_with(|| Vec::new())
                        .push(note.pch);
 
-        handle.send_midi(MidiMsg::On(note.cha, note.pch, note.vel));
+        handle.send_midi(beat, MidiMsg::On(note.cha, note.pch, note.vel));
     }
 
-    fn resolve(&mut self, _handle: &JackHandle) -> bool {
+    fn resolve(&mut self, _handle: &dyn MidiSink) -> bool {
         self.current = None;
         self.held || !self.off_events.is_empty()
     }
@@ -261,7 +333,7 @@ impl Solo {
 
 impl Filter for Solo {
 
-    fn activate(&mut self, beat: u64, _handle: &JackHandle) {
+    fn activate(&mut self, beat: u64, _handle: &dyn MidiSink) {
         if self.current.is_some() {
             panic!("Solo::activate on unresolved filter");
         }
@@ -288,20 +360,18 @@ impl Filter for Solo {
         }
     }
 
-    fn push(&mut self, note: &Note, _handle: &JackHandle) {
+    fn push(&mut self, note: &Note, _handle: &dyn MidiSink) {
         let beat = self.current.expect("Solo::push without activate");
 
         self.active.push_back((beat + note.dur as u64, note.pch, note.vel));
     }
 
-    fn resolve(&mut self, handle: &JackHandle) -> bool {
-        if self.current.is_none() {
-            panic!("Solo::resolve without activate");
-        }
+    fn resolve(&mut self, handle: &dyn MidiSink) -> bool {
+        let beat = self.current.expect("Solo::resolve without activate");
         self.current = None;
         if self.active.is_empty() {
             if let Some(oldpch) = self.playing {
-                handle.send_midi(MidiMsg::Off(self.channel, oldpch));
+                handle.send_midi(beat, MidiMsg::Off(self.channel, oldpch));
                 self.playing = None;
             }
             return false
@@ -313,12 +383,12 @@ impl Filter for Solo {
 
         match self.playing {
             None => {
-                handle.send_midi(MidiMsg::On(self.channel, pch, vel));
+                handle.send_midi(beat, MidiMsg::On(self.channel, pch, vel));
             },
             Some(oldpch) => {
                 if oldpch != pch {
-                    handle.send_midi(MidiMsg::Off(self.channel, oldpch));
-                    handle.send_midi(MidiMsg::On(self.channel, pch, vel));
+                    handle.send_midi(beat, MidiMsg::Off(self.channel, oldpch));
+                    handle.send_midi(beat, MidiMsg::On(self.channel, pch, vel));
                 }
             }
         }
@@ -353,19 +423,19 @@ impl RandomArp {
 }
 
 impl Filter for RandomArp {
-    fn activate(&mut self, beat: u64, _handle: &JackHandle) {
+    fn activate(&mut self, beat: u64, _handle: &dyn MidiSink) {
         if self.current.is_some() {
             panic!("RandomArp::activate without resolve.")
         }
         self.current = Some(beat);
     }
 
-    fn push(&mut self, note: &Note, _handle: &JackHandle) {
+    fn push(&mut self, note: &Note, _handle: &dyn MidiSink) {
         let beat = self.current.expect("RandomArp::push without activate.");
         self.active.push((beat + note.dur as u64, note.pch, note.vel));
     }
 
-    fn resolve(&mut self, handle: &JackHandle) -> bool {
+    fn resolve(&mut self, handle: &dyn MidiSink) -> bool {
         let beat = self.current.expect("RandomArp::resolve without activate.");
         self.current = None;
 
@@ -373,7 +443,7 @@ impl Filter for RandomArp {
             None => true,
             Some((change_beat, pch)) => {
                 if change_beat == beat {
-                    handle.send_midi(MidiMsg::Off(self.channel, pch));
+                    handle.send_midi(beat, MidiMsg::Off(self.channel, pch));
                     true
                 } else {
                     false
@@ -393,7 +463,7 @@ impl Filter for RandomArp {
             let mut rng = rand::thread_rng();
             let i = rng.gen_range(0, self.active.len());
             let (_, pch, vel) = self.active[i];
-            handle.send_midi(MidiMsg::On(self.channel, pch, vel));
+            handle.send_midi(beat, MidiMsg::On(self.channel, pch, vel));
             self.next_change = Some((beat + dur, pch));
         }
 
@@ -437,19 +507,19 @@ impl Arp {
 }
 
 impl Filter for Arp {
-    fn activate(&mut self, beat: u64, _handle: &JackHandle) {
+    fn activate(&mut self, beat: u64, _handle: &dyn MidiSink) {
         if self.current.is_some() {
             panic!("Arp::activate without resolve")
         }
         self.current = Some(beat)
     }
 
-    fn push(&mut self, note: &Note, _handle: &JackHandle) {
+    fn push(&mut self, note: &Note, _handle: &dyn MidiSink) {
         let beat = self.current.expect("Arp::push without activate.");
         self.pending.push((beat + note.dur as u64, note.pch, note.vel));
     }
 
-    fn resolve(&mut self, handle: &JackHandle) -> bool {
+    fn resolve(&mut self, handle: &dyn MidiSink) -> bool {
         let beat = self.current.expect("Arp::resolve without activate.");
         self.current = None;
 
@@ -457,7 +527,7 @@ impl Filter for Arp {
             None => None,
             Some((change_beat, pch)) => {
                 if change_beat == beat {
-                    handle.send_midi(MidiMsg::Off(self.channel, pch));
+                    handle.send_midi(beat, MidiMsg::Off(self.channel, pch));
                     Some(pch)
                 } else {
                     return true;
@@ -507,7 +577,7 @@ impl Filter for Arp {
                 _ => panic!("invalid current_direction")
             };
             let (_, pch, vel) = self.active[i];
-            handle.send_midi(MidiMsg::On(self.channel, pch, vel));
+            handle.send_midi(beat, MidiMsg::On(self.channel, pch, vel));
             self.next_change = Some((beat + dur, pch));
             return true;
         }
@@ -558,26 +628,34 @@ impl Filter for Arp {
         };
 
         let (_, pch, vel) = self.active[i];
-        handle.send_midi(MidiMsg::On(self.channel, pch, vel));
+        handle.send_midi(beat, MidiMsg::On(self.channel, pch, vel));
         self.next_change = Some((beat + dur, pch));
         true
     }
 }
 
-pub struct MidiBridge<'a> {
-    handle: &'a JackHandle,
+pub struct MidiBridge<'a, S: MidiSink> {
+    handle: &'a S,
     beat: u64,
+    due: Option<f64>,
     filter_specs: [FilterSpec; 256],
+    // The port and MIDI channel each configured channel plays on.
+    outputs: Vec<Option<(Rc<str>, u8)>>,
     filters: BTreeMap<u8, Box<dyn Filter>>
 }
 
-impl<'a> MidiBridge<'a> {
-    pub fn new(conf: &FungedConfig, handle: &'a JackHandle) -> Self {
+impl<'a, S: MidiSink> MidiBridge<'a, S> {
+    pub fn new(conf: &FungedConfig, handle: &'a S) -> Self {
+        MidiBridge::from_channels(&conf.channels, handle)
+    }
+
+    // Channels missing from the slice use the Basic filter.
+    pub fn from_channels(channels: &[Option<ChannelConfig>],
+                         handle: &'a S) -> Self {
         let mut specs = arr![FilterSpec::Basic; 256];
 
-        for ch in 0..=255 {
-            let filt = conf.channels[ch as usize].as_ref()
-                            .and_then(|cc| cc.note_filter.as_ref());
+        for (ch, cc) in channels.iter().enumerate() {
+            let filt = cc.as_ref().and_then(|cc| cc.note_filter.as_ref());
             let spec = match filt {
                 None => continue,
                 Some(s) => {
@@ -588,20 +666,31 @@ impl<'a> MidiBridge<'a> {
                 }
             };
 
-            specs[ch as usize] = spec;
+            specs[ch] = spec;
         }
 
+        let outputs = channels.iter().enumerate().map(|(ch, cc)| {
+            cc.as_ref().map(|cc| (Rc::clone(&cc.local),
+                                  cc.midi_channel(ch as u8)))
+        }).collect();
+
         MidiBridge {
             handle: handle,
             beat: 0,
+            due: None,
             filter_specs: specs,
+            outputs: outputs,
             filters: BTreeMap::new(),
         }
     }
 
     fn step_i(&mut self, beat: u64, log: &Vec<EventLog>) {
         let mut filters = mem::take(&mut self.filters);
-        let handle = self.handle;
+        let at = self.due.map(|t| AtTick { sink: self.handle, tick: t });
+        let handle: &dyn MidiSink = match &at {
+            Some(at) => at,
+            None => self.handle,
+        };
 
         for filt in filters.values_mut() {
             filt.activate(beat, handle);
@@ -609,7 +698,7 @@ impl<'a> MidiBridge<'a> {
 
         for ev in log {
             let note = match ev {
-                EventLog::Play(n) => n,
+                EventLog::Play(_, n) => n,
                 _ => continue
             };
             if note.pch > 127 { continue }
@@ -621,7 +710,7 @@ impl<'a> MidiBridge<'a> {
                     f.activate(beat, handle);
                     f
                 });
-            act.push(note, self.handle);
+            act.push(note, handle);
         }
 
         let mut dead = Vec::new();
@@ -638,6 +727,29 @@ impl<'a> MidiBridge<'a> {
         self.filters = filters;
     }
 
+    // The clock tick that messages from the following steps are due at.
+    pub fn due_at(&mut self, tick: f64) {
+        self.due = Some(tick);
+    }
+
+    // Turn off everything that might be sounding and forget any notes the
+    // filters were holding on to. Channels sharing a port and MIDI channel
+    // only need one all notes off between them. They're due with the last
+    // step's notes, so they can't go out ahead of them.
+    pub fn silence(&mut self, beat: u64) {
+        let mut done = HashSet::new();
+        for ch in mem::take(&mut self.filters).keys() {
+            if let Some(Some(out)) = self.outputs.get(*ch as usize) {
+                if !done.insert(out.clone()) { continue }
+            }
+            let msg = MidiMsg::AllOff(*ch);
+            match self.due {
+                Some(tick) => self.handle.send_midi_at(beat, tick, msg),
+                None => self.handle.send_midi(beat, msg),
+            };
+        }
+    }
+
     pub fn step(&mut self, beat: u64, log: &Vec<EventLog>) {
         if beat < self.beat {
             panic!("Beat went back in time!!!");
@@ -653,3 +765,175 @@ impl<'a> MidiBridge<'a> {
 
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use MidiMsg::{On, Off};
+
+    // Feed (pch, dur) notes on channel 0 through a bridge, one slice per
+    // beat, and return what comes out.
+    fn run(filter: Option<&str>, beats: &[&[(u8, u8)]])
+        -> Vec<(u64, MidiMsg)> {
+        let cc = ChannelConfig { local: Rc::from("out"),
+                                 starting: 0,
+                                 bank: None,
+                                 program: None,
+                                 pan: None,
+                                 note_filter: filter.map(String::from) };
+        let sink = RecordingSink::new();
+        let mut bridge = MidiBridge::from_channels(&[Some(cc)], &sink);
+        for (beat, notes) in beats.iter().enumerate() {
+            let log: Vec<EventLog> = notes.iter().map(|(pch, dur)|
+                EventLog::Play(1, Note::new(0, *pch, 100, *dur))).collect();
+            bridge.step(beat as u64, &log);
+        }
+        sink.take()
+    }
+
+    #[test]
+    fn basic() {
+        assert_eq!(run(None, &[&[(60, 2), (60, 1), (64, 1)], &[],
+                               &[(60, 1)], &[]]),
+                   vec![(0, On(0, 60, 100)), (0, On(0, 64, 100)),
+                        (1, Off(0, 64)), (2, Off(0, 60)),
+                        (2, On(0, 60, 100)), (3, Off(0, 60))]);
+        assert_eq!(run(None, &[&[(60, 0), (200, 1)]]), vec![]);
+    }
+
+    #[test]
+    fn pause() {
+        assert_eq!(run(Some("pause:2"), &[&[(60, 1)], &[(60, 1)], &[(60, 1)],
+                                          &[(60, 1)], &[]]),
+                   vec![(0, On(0, 60, 100)), (1, Off(0, 60)),
+                        (3, On(0, 60, 100)), (4, Off(0, 60))]);
+    }
+
+    #[test]
+    fn silence() {
+        let sink = RecordingSink::new();
+        let mut bridge = MidiBridge::from_channels(&[], &sink);
+        bridge.step(0, &vec![EventLog::Play(1, Note::new(3, 60, 100, 4))]);
+        bridge.silence(1);
+        for beat in 1..6 {
+            bridge.step(beat, &Vec::new());
+        }
+        assert_eq!(sink.take(), vec![(0, On(3, 60, 100)),
+                                     (1, MidiMsg::AllOff(3))]);
+
+        // A range of more than 16 channels wraps, so channels 16 and 32
+        // both play on MIDI channel 0 of one port, as in every backend.
+        let cc = |starting| Some(ChannelConfig { local: Rc::from("out"),
+                                                 starting: starting,
+                                                 bank: None,
+                                                 program: None,
+                                                 pan: None,
+                                                 note_filter: None });
+        let mut channels : Vec<_> = (0..33).map(|_| None).collect();
+        channels[16] = cc(16);
+        channels[32] = cc(16);
+        let mut bridge = MidiBridge::from_channels(&channels, &sink);
+        bridge.step(0, &vec![EventLog::Play(1, Note::new(16, 60, 100, 4)),
+                             EventLog::Play(1, Note::new(32, 60, 100, 4))]);
+        bridge.silence(1);
+        assert_eq!(sink.take()[2..], [(1, MidiMsg::AllOff(16))]);
+    }
+
+    // Keeps the tick each message was sent for.
+    #[derive(Default)]
+    struct TimedSink(RefCell<Vec<(f64, MidiMsg)>>);
+
+    impl MidiSink for TimedSink {
+        fn send_midi(&self, beat: u64, msg: MidiMsg) -> bool {
+            self.send_midi_at(beat, f64::NEG_INFINITY, msg)
+        }
+
+        fn send_midi_at(&self, _beat: u64, tick: f64, msg: MidiMsg) -> bool {
+            self.0.borrow_mut().push((tick, msg));
+            true
+        }
+    }
+
+    #[test]
+    fn due_at() {
+        let sink = TimedSink::default();
+        let mut bridge = MidiBridge::from_channels(&[], &sink);
+        bridge.step(0, &vec![EventLog::Play(1, Note::new(3, 60, 100, 1))]);
+        bridge.due_at(8.5);
+        bridge.step(1, &vec![EventLog::Play(1, Note::new(3, 62, 90, 1))]);
+        assert_eq!(sink.0.take(), vec![(f64::NEG_INFINITY, On(3, 60, 100)),
+                                       (8.5, Off(3, 60)),
+                                       (8.5, On(3, 62, 90))]);
+
+        // Stopping turns notes off no earlier than the last ones started.
+        bridge.silence(1);
+        assert_eq!(sink.0.take(), vec![(8.5, MidiMsg::AllOff(3))]);
+    }
+
+    #[test]
+    fn solo() {
+        assert_eq!(run(Some("solo"), &[&[(60, 4), (64, 2)], &[], &[], &[],
+                                       &[]]),
+                   vec![(0, On(0, 64, 100)), (2, Off(0, 64)),
+                        (2, On(0, 60, 100)), (4, Off(0, 60))]);
+    }
+
+    #[test]
+    fn random_arp() {
+        assert_eq!(run(Some("random:2"), &[&[(60, 4)], &[], &[], &[], &[]]),
+                   vec![(0, On(0, 60, 100)), (2, Off(0, 60)),
+                        (2, On(0, 60, 100)), (4, Off(0, 60))]);
+    }
+
+    #[test]
+    fn arp() {
+        let chord = |dur| [(64, dur), (60, dur), (67, dur)];
+        let up = [&chord(5)[..], &[], &[], &[], &[], &[]];
+        assert_eq!(run(Some("up:1"), &up),
+                   vec![(0, On(0, 60, 100)), (1, Off(0, 60)),
+                        (1, On(0, 64, 100)), (2, Off(0, 64)),
+                        (2, On(0, 67, 100)), (3, Off(0, 67)),
+                        (3, On(0, 60, 100)), (4, Off(0, 60)),
+                        (4, On(0, 64, 100)), (5, Off(0, 64))]);
+
+        let down = [&chord(4)[..], &[], &[], &[], &[]];
+        assert_eq!(run(Some("down:1"), &down),
+                   vec![(0, On(0, 67, 100)), (1, Off(0, 67)),
+                        (1, On(0, 64, 100)), (2, Off(0, 64)),
+                        (2, On(0, 60, 100)), (3, Off(0, 60)),
+                        (3, On(0, 67, 100)), (4, Off(0, 67))]);
+
+        assert_eq!(run(Some("bi:1"), &up),
+                   vec![(0, On(0, 60, 100)), (1, Off(0, 60)),
+                        (1, On(0, 64, 100)), (2, Off(0, 64)),
+                        (2, On(0, 67, 100)), (3, Off(0, 67)),
+                        (3, On(0, 64, 100)), (4, Off(0, 64)),
+                        (4, On(0, 60, 100)), (5, Off(0, 60))]);
+
+        // Durations cycle, and the last note is held for its full duration
+        // even though the chord has ended.
+        let slow = [&chord(4)[..], &[], &[], &[], &[], &[]];
+        assert_eq!(run(Some("up:2:1"), &slow),
+                   vec![(0, On(0, 60, 100)), (2, Off(0, 60)),
+                        (2, On(0, 64, 100)), (3, Off(0, 64)),
+                        (3, On(0, 67, 100)), (5, Off(0, 67))]);
+    }
+
+    #[test]
+    fn bad_specs() {
+        assert!(FilterSpec::parse("solo:1").is_err());
+        assert!(FilterSpec::parse("random").is_err());
+        assert!(FilterSpec::parse("pause:1:2").is_err());
+        assert!(FilterSpec::parse("sideways:1").is_err());
+        assert!(FilterSpec::parse("up:x").is_err());
+    }
+
+    #[test]
+    fn spec_info() {
+        let info = FilterSpec::parse("down:1:3:1").unwrap().info();
+        assert_eq!(info, FilterInfo { kind: "down".to_string(),
+                                      durations: vec![1, 3, 1] });
+        let info = FilterSpec::parse("pause:2").unwrap().info();
+        assert_eq!(info.durations, vec![2]);
+    }
+}
@@ -0,0 +1,530 @@
+/*
+    Noisefunge Copyright (C) 2021 Rev. Johnny Healey <rev.null@gmail.com>
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use log::*;
+use std::convert::TryInto;
+use std::fs;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::path::{Component, Path, PathBuf};
+use std::thread;
+use std::time::Instant;
+use crossbeam_channel::Sender;
+
+use crate::api::{KillReq, NewProcessReq};
+use crate::befunge::{Engine, EventLog, SourceMode};
+use crate::clock::clamp_bpm;
+use crate::config::FungedConfig;
+use crate::server::{FungeRequest, Responder, token_eq};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OscArg {
+    Int(i32),
+    Float(f32),
+    Str(String),
+    Blob(Vec<u8>)
+}
+
+impl OscArg {
+    // Controllers often send faders and buttons as floats, so accept either.
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            OscArg::Int(i) => Some(*i as i64),
+            OscArg::Float(f) => Some(*f as i64),
+            _ => None
+        }
+    }
+
+    pub fn as_float(&self) -> Option<f64> {
+        match self {
+            OscArg::Int(i) => Some(*i as f64),
+            OscArg::Float(f) => Some(*f as f64),
+            _ => None
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            OscArg::Str(s) => Some(s),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OscMessage {
+    pub addr: String,
+    pub args: Vec<OscArg>
+}
+
+fn pad(buf: &mut Vec<u8>) {
+    while buf.len() % 4 != 0 {
+        buf.push(0);
+    }
+}
+
+fn write_str(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(s.as_bytes());
+    buf.push(0);
+    pad(buf);
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.0.len() < n {
+            return Err("Truncated OSC packet".to_string());
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn int(&mut self) -> Result<i32, String> {
+        Ok(i32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let end = self.0.iter().position(|b| *b == 0)
+                      .ok_or("Unterminated OSC string")?;
+        let s = String::from_utf8(self.0[..end].to_vec())
+                      .map_err(|_| "OSC string is not UTF-8")?;
+        self.take((end + 4) & !3)?;
+        Ok(s)
+    }
+}
+
+impl OscMessage {
+    pub fn new(addr: &str, args: Vec<OscArg>) -> Self {
+        OscMessage { addr: addr.to_string(), args: args }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        write_str(&mut buf, &self.addr);
+        let tags : String = self.args.iter().map(|a| match a {
+            OscArg::Int(_) => 'i',
+            OscArg::Float(_) => 'f',
+            OscArg::Str(_) => 's',
+            OscArg::Blob(_) => 'b',
+        }).collect();
+        write_str(&mut buf, &format!(",{}", tags));
+        for arg in &self.args {
+            match arg {
+                OscArg::Int(i) => buf.extend_from_slice(&i.to_be_bytes()),
+                OscArg::Float(f) => buf.extend_from_slice(&f.to_be_bytes()),
+                OscArg::Str(s) => write_str(&mut buf, s),
+                OscArg::Blob(b) => {
+                    buf.extend_from_slice(&(b.len() as i32).to_be_bytes());
+                    buf.extend_from_slice(b);
+                    pad(&mut buf);
+                }
+            }
+        }
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> Result<Self, String> {
+        let mut rd = Reader(buf);
+        let addr = rd.string()?;
+        if rd.0.is_empty() {
+            return Ok(OscMessage::new(&addr, Vec::new()));
+        }
+        let tags = rd.string()?;
+        if !tags.starts_with(',') {
+            return Err(format!("Bad OSC type tags: {}", tags));
+        }
+        let mut args = Vec::new();
+        for tag in tags.chars().skip(1) {
+            args.push(match tag {
+                'i' => OscArg::Int(rd.int()?),
+                'f' => OscArg::Float(f32::from_bits(rd.int()? as u32)),
+                's' | 'S' => OscArg::Str(rd.string()?),
+                'b' => {
+                    let len = rd.int()?.max(0) as usize;
+                    let blob = rd.take(len)?.to_vec();
+                    rd.take((4 - len % 4) % 4)?;
+                    OscArg::Blob(blob)
+                },
+                t => return Err(format!("Unsupported OSC type: {}", t)),
+            });
+        }
+        Ok(OscMessage::new(&addr, args))
+    }
+}
+
+// Decode a packet into its messages. Bundles are flattened, and their time
+// tags are ignored.
+pub fn decode_packet(buf: &[u8]) -> Result<Vec<OscMessage>, String> {
+    if !buf.starts_with(b"#bundle\0") {
+        return Ok(vec![OscMessage::decode(buf)?]);
+    }
+    let mut rd = Reader(&buf[8..]);
+    rd.take(8)?;
+    let mut msgs = Vec::new();
+    while !rd.0.is_empty() {
+        let len = rd.int()?.max(0) as usize;
+        msgs.extend(decode_packet(rd.take(len)?)?);
+    }
+    Ok(msgs)
+}
+
+// Programs started over OSC must be under dir, so a relative path that
+// doesn't climb out of it is all that's accepted.
+fn program_path(dir: &Path, path: &str) -> Result<PathBuf, String> {
+    let ok = Path::new(path).components().all(|c| match c {
+        Component::Normal(_) | Component::CurDir => true,
+        _ => false,
+    });
+    if !ok {
+        return Err(format!("{} is not a relative path under {}",
+                           path, dir.display()));
+    }
+    Ok(dir.join(path))
+}
+
+// Map an OSC message onto the request it stands for.
+//   /process path [name]    - start the program in a file under dir
+//   /kill pid...            - kill by pid
+//   /kill name...           - kill by name
+//   /kill/all
+//   /channel ch value...    - inject values into a channel
+//   /tempo bpm              - set the internal clock's tempo
+//   /tempo/tap
+//   /transport running      - stop (0) or start (1) the internal clock
+fn to_request(msg: &OscMessage, dir: &Path)
+    -> Result<FungeRequest, String> {
+    let ints = || msg.args.iter().map(|a| a.as_int())
+                          .collect::<Option<Vec<i64>>>();
+    let strs = || msg.args.iter().map(|a| a.as_str().map(String::from))
+                          .collect::<Option<Vec<String>>>();
+    let byte = |i: i64| if i >= 0 && i <= 255 { Ok(i as u8) } else {
+        Err(format!("{} is not a byte", i))
+    };
+
+    match msg.addr.as_str() {
+        "/process" => {
+            let path = msg.args.get(0).and_then(|a| a.as_str())
+                          .ok_or("/process needs a path")?;
+            let name = match msg.args.get(1) {
+                None => path,
+                Some(a) => a.as_str().ok_or("Bad /process name")?,
+            };
+            let prog = fs::read_to_string(program_path(dir, path)?)
+                .map_err(|e| format!("Failed to read {}: {}", path, e))?;
+            Ok(FungeRequest::StartProcess(
+                NewProcessReq {
+                    name: Some(name.to_string()),
+                    program: prog.trim_end_matches('\n').to_string(),
+                    mode: SourceMode::Raw,
+                    strict: false
+                },
+                Responder::new()))
+        },
+        "/kill/all" => Ok(FungeRequest::Kill(KillReq::All)),
+        "/kill" if msg.args.is_empty() => Err("/kill needs pids".to_string()),
+        "/kill" => {
+            if let Some(pids) = ints() {
+                Ok(FungeRequest::Kill(KillReq::Pids(
+                    pids.into_iter().map(|p| p as u64).collect())))
+            } else if let Some(names) = strs() {
+                Ok(FungeRequest::Kill(KillReq::Names(names)))
+            } else {
+                Err("/kill needs all pids or all names".to_string())
+            }
+        },
+        "/channel" => {
+            let vals = ints().ok_or("/channel needs integers")?;
+            if vals.len() < 2 {
+                return Err("/channel needs a channel and values".to_string());
+            }
+            let vals = vals.into_iter().map(byte)
+                           .collect::<Result<Vec<u8>, String>>()?;
+            Ok(FungeRequest::Send(vals[0], vals[1..].to_vec(),
+                                  Responder::new()))
+        },
+        "/tempo" => {
+            match msg.args.get(0).and_then(|a| a.as_float())
+                                 .and_then(clamp_bpm) {
+                Some(bpm) =>
+                    Ok(FungeRequest::SetTempo(bpm, Responder::new())),
+                _ => Err("/tempo needs a positive bpm".to_string()),
+            }
+        },
+        "/tempo/tap" => Ok(FungeRequest::Tap(Instant::now(),
+                                             Responder::new())),
+        "/transport" => {
+            let running = msg.args.get(0).and_then(|a| a.as_int())
+                             .ok_or("/transport needs 0 or 1")?;
+            Ok(FungeRequest::SetRunning(running != 0, Responder::new()))
+        },
+        addr => Err(format!("Unknown OSC address: {}", addr)),
+    }
+}
+
+// With a token, every message must start with it as a string argument,
+// which is removed before the message is handled.
+fn check_token(msg: &mut OscMessage, token: &Option<String>)
+    -> Result<(), String> {
+    let token = match token {
+        Some(t) => t,
+        None => return Ok(()),
+    };
+    match msg.args.first().and_then(|a| a.as_str()) {
+        Some(t) if token_eq(token, t) => {
+            msg.args.remove(0);
+            Ok(())
+        },
+        _ => Err(format!("{} without a valid token", msg.addr)),
+    }
+}
+
+pub fn serve(socket: UdpSocket, dir: PathBuf, token: Option<String>,
+             sender: Sender<FungeRequest>) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut buf = [0; 65536];
+        loop {
+            let (len, from) = match socket.recv_from(&mut buf) {
+                Ok(r) => r,
+                Err(e) => {
+                    error!("OSC socket failed: {:?}", e);
+                    return
+                }
+            };
+            let msgs = match decode_packet(&buf[..len]) {
+                Ok(msgs) => msgs,
+                Err(e) => {
+                    warn!("Bad OSC packet from {}: {}", from, e);
+                    continue
+                }
+            };
+            for mut msg in msgs {
+                let checked = check_token(&mut msg, &token);
+                let req = match checked.and_then(|_| to_request(&msg, &dir)) {
+                    Ok(req) => req,
+                    Err(e) => {
+                        warn!("Bad OSC message from {}: {}", from, e);
+                        continue
+                    }
+                };
+                let responder = match &req {
+                    FungeRequest::StartProcess(_, r) =>
+                        Some(r.clone()),
+                    _ => None
+                };
+                sender.send(req).expect("Sender::send failed");
+                match responder.and_then(|r| r.wait()) {
+                    Some(Ok(pid)) => info!("OSC started process: {}", pid),
+                    Some(Err(e)) => warn!("OSC process failed: {}", e),
+                    None => {}
+                }
+            }
+        }
+    })
+}
+
+// Mirrors engine events to OSC listeners. Every message starts with the
+// beat, pid and process name (empty if unnamed).
+//   /noisefunge/play beat pid name cha pch vel dur
+//   /noisefunge/print_char beat pid name char
+//   /noisefunge/print_num beat pid name num
+//   /noisefunge/crashed beat pid name reason
+//   /noisefunge/new_process beat pid name
+pub struct OscOutput {
+    socket: Option<UdpSocket>,
+    destinations: Vec<SocketAddr>
+}
+
+impl OscOutput {
+    pub fn new(conf: &FungedConfig) -> Self {
+        let destinations : Vec<SocketAddr> = conf.osc_out.iter().flat_map(|d|
+            d.to_socket_addrs()
+             .expect(&format!("Bad OSC destination: {}", d))
+        ).collect();
+        let socket = if destinations.is_empty() {
+            None
+        } else {
+            Some(UdpSocket::bind("0.0.0.0:0")
+                     .expect("Failed to bind OSC output socket"))
+        };
+        OscOutput { socket: socket, destinations: destinations }
+    }
+
+    fn event_message(beat: u64, ev: &EventLog, engine: &Engine)
+        -> Option<OscMessage> {
+        let (addr, pid, mut args) = match ev {
+            EventLog::Play(pid, n) =>
+                ("/noisefunge/play", *pid,
+                 vec![OscArg::Int(n.cha as i32), OscArg::Int(n.pch as i32),
+                      OscArg::Int(n.vel as i32), OscArg::Int(n.dur as i32)]),
+            EventLog::PrintChar(pid, c) =>
+                ("/noisefunge/print_char", *pid, vec![OscArg::Int(*c as i32)]),
+            EventLog::PrintNum(pid, c) =>
+                ("/noisefunge/print_num", *pid, vec![OscArg::Int(*c as i32)]),
+            EventLog::Crashed(pid, reason) =>
+                ("/noisefunge/crashed", *pid,
+                 vec![OscArg::Str(format!("{:?}", reason))]),
+            EventLog::NewProcess(pid) =>
+                ("/noisefunge/new_process", *pid, Vec::new()),
+            _ => return None,
+        };
+        let name = engine.process_name(pid).unwrap_or_default();
+        let mut tagged = vec![OscArg::Int(beat as i32),
+                              OscArg::Int(pid as i32),
+                              OscArg::Str(name)];
+        tagged.append(&mut args);
+        Some(OscMessage::new(addr, tagged))
+    }
+
+    pub fn step(&self, beat: u64, log: &Vec<EventLog>, engine: &Engine) {
+        let socket = match &self.socket {
+            Some(s) => s,
+            None => return,
+        };
+        for ev in log {
+            let buf = match OscOutput::event_message(beat, ev, engine) {
+                Some(msg) => msg.encode(),
+                None => continue,
+            };
+            for dest in &self.destinations {
+                if let Err(e) = socket.send_to(&buf, dest) {
+                    debug!("Failed to send OSC to {}: {:?}", dest, e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam_channel::bounded;
+    use std::time::Duration;
+
+    #[test]
+    fn loopback() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let (snd, rcv) = bounded(4);
+        let dir = std::env::temp_dir();
+        serve(socket, dir.clone(), None, snd);
+
+        let path = dir.join("noisefunge_osc_test.b98");
+        fs::write(&path, "1.@\n").unwrap();
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let send = |msg: OscMessage| {
+            let buf = msg.encode();
+            assert_eq!(decode_packet(&buf).unwrap(), vec![msg]);
+            client.send_to(&buf, addr).unwrap();
+        };
+        let recv = || rcv.recv_timeout(Duration::from_secs(2)).unwrap();
+
+        send(OscMessage::new("/kill", vec![OscArg::Int(1),
+                                           OscArg::Float(2.0)]));
+        match recv() {
+            FungeRequest::Kill(KillReq::Pids(p)) => assert_eq!(p, vec![1, 2]),
+            r => panic!("Unexpected request: {:?}", r),
+        }
+
+        send(OscMessage::new("/kill", vec![OscArg::Str("a".to_string())]));
+        match recv() {
+            FungeRequest::Kill(KillReq::Names(n)) => assert_eq!(n, vec!["a"]),
+            r => panic!("Unexpected request: {:?}", r),
+        }
+
+        // Out of range values are dropped.
+        send(OscMessage::new("/channel", vec![OscArg::Int(3),
+                                              OscArg::Int(300)]));
+        send(OscMessage::new("/channel", vec![OscArg::Int(3), OscArg::Int(1),
+                                              OscArg::Int(2)]));
+        match recv() {
+            FungeRequest::Send(3, v, _) => assert_eq!(v, vec![1, 2]),
+            r => panic!("Unexpected request: {:?}", r),
+        }
+
+        send(OscMessage::new("/tempo", vec![OscArg::Int(0)]));
+        send(OscMessage::new("/tempo", vec![OscArg::Float(132.5)]));
+        match recv() {
+            FungeRequest::SetTempo(bpm, _) => assert_eq!(bpm, 132.5),
+            r => panic!("Unexpected request: {:?}", r),
+        }
+
+        // Only paths under the program directory are read.
+        let abs = path.to_str().unwrap().to_string();
+        send(OscMessage::new("/process", vec![OscArg::Str(abs)]));
+        let up = "../noisefunge_osc_test.b98".to_string();
+        send(OscMessage::new("/process", vec![OscArg::Str(up)]));
+        let rel = "noisefunge_osc_test.b98".to_string();
+        send(OscMessage::new("/process",
+                             vec![OscArg::Str(rel),
+                                  OscArg::Str("osc".to_string())]));
+        match recv() {
+            FungeRequest::StartProcess(req, rspndr) => {
+                assert_eq!(req.name, Some("osc".to_string()));
+                assert_eq!(req.program, "1.@");
+                rspndr.respond(Ok(1));
+            },
+            r => panic!("Unexpected request: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn token() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let (snd, rcv) = bounded(4);
+        serve(socket, std::env::temp_dir(), Some("ctl".to_string()), snd);
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let send = |args: Vec<OscArg>| {
+            let buf = OscMessage::new("/kill", args).encode();
+            client.send_to(&buf, addr).unwrap();
+        };
+
+        // Kills without the token are dropped.
+        send(vec![OscArg::Int(1)]);
+        send(vec![OscArg::Str("nope".to_string()), OscArg::Int(2)]);
+        send(vec![OscArg::Str("ctl".to_string()), OscArg::Int(3)]);
+        match rcv.recv_timeout(Duration::from_secs(2)).unwrap() {
+            FungeRequest::Kill(KillReq::Pids(p)) => assert_eq!(p, vec![3]),
+            r => panic!("Unexpected request: {:?}", r),
+        }
+        assert!(rcv.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn event_messages() {
+        let mut eng = Engine::new(24);
+        eng.make_process(Some("p".to_string()),
+                         crate::befunge::Prog::parse("7.@").unwrap());
+        let mut msgs = Vec::new();
+        let mut beat = 0;
+        while msgs.is_empty() && beat < 10 {
+            let (b, log) = eng.step();
+            beat = b;
+            msgs = log.iter().filter_map(|ev|
+                OscOutput::event_message(beat, ev, &eng)).collect();
+        }
+        // The process is gone by now, but its name is still known.
+        let reason = OscArg::Str("PopFromEmptyStack".to_string());
+        assert_eq!(msgs, vec![
+            OscMessage::new("/noisefunge/crashed",
+                            vec![OscArg::Int(beat as i32), OscArg::Int(1),
+                                 OscArg::Str("p".to_string()), reason])]);
+    }
+}
@@ -15,26 +15,54 @@
     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-use rouille::{Request, Response, router, try_or_400};
+use rouille::{Request, Response, ResponseBody, ReadWrite, Upgrade, router,
+             try_or_400};
 use log::*;
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::net::{SocketAddr, UdpSocket};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::Path;
+use std::process;
 use std::thread;
 use std::sync::{Arc, Mutex, Condvar};
-use std::time::Duration;
-use crossbeam_channel::{bounded, Sender, Receiver};
+use std::time::{Duration, Instant};
+use crossbeam_channel::{bounded, Sender, Receiver, RecvTimeoutError,
+                        TrySendError};
 
+use crate::befunge::{EventLog, ParseError};
 use crate::config::{FungedConfig};
 use crate::api::*;
+use crate::clock::clamp_bpm;
+use crate::osc;
+
+// Number of beats of events kept for clients resuming with since=beat.
+const EVENT_HISTORY: u64 = 4096;
+
+// Number of unsent chunks before a slow subscriber is dropped.
+const EVENT_BUFFER: usize = 1024;
+
+const EVENT_KEEPALIVE: Duration = Duration::from_secs(15);
+
+// Threads handling HTTP requests. Long polls and event streams each hold one
+// for as long as they last.
+const HTTP_WORKERS: usize = 64;
+
+// Requests on the Unix socket that can wait for a worker before more are
+// turned away.
+const UNIX_BACKLOG: usize = 64;
 
 #[derive(Debug,Clone)]
 pub struct Responder<T>(Arc<(Mutex<Option<T>>, Condvar)>);
 unsafe impl<T: Send> Send for Responder<T> {}
 
 impl<T> Responder<T> {
-    fn new() -> Self {
+    pub fn new() -> Self {
         Responder(Arc::new((Mutex::new(None), Condvar::new())))
     }
 
-    fn wait(&self) -> Option<T> {
+    pub fn wait(&self) -> Option<T> {
         let Responder(arc) = self;
         let lock = &arc.0;
         let cond = &arc.1;
@@ -61,11 +89,142 @@ impl<T> Responder<T> {
     }
 }
 
+#[derive(Debug, Copy, Clone)]
+pub enum SubscribeError {
+    Expired(u64), // Oldest beat that can be resumed from
+    Future(u64),  // Current beat
+}
+
+// Frame a beat's events for the stream. JSON streams are server-sent events;
+// MessagePack streams are just a sequence of values.
+fn event_chunk(enc: Encoding, events: &BeatEvents) -> Arc<Vec<u8>> {
+    Arc::new(match enc {
+        Encoding::Json =>
+            format!("id: {}\ndata: {}\n\n", events.beat,
+                    serde_json::to_string(events).unwrap()).into_bytes(),
+        Encoding::MsgPack => enc.encode(&Some(events)),
+    })
+}
+
+fn keepalive_chunk(enc: Encoding) -> Arc<Vec<u8>> {
+    Arc::new(match enc {
+        Encoding::Json => b":\n\n".to_vec(),
+        Encoding::MsgPack => enc.encode(&None::<BeatEvents>),
+    })
+}
+
+// Keeps a window of recent events, encoded for each stream format, and
+// forwards new ones to subscribed clients.
+pub struct EventBroadcast {
+    beat: u64,
+    floor: Option<u64>,
+    history: VecDeque<(u64, Arc<Vec<u8>>, Arc<Vec<u8>>)>, // beat, json, msgpack
+    subscribers: Vec<(Encoding, Sender<Arc<Vec<u8>>>)>,
+}
+
+impl EventBroadcast {
+    pub fn new() -> Self {
+        EventBroadcast {
+            beat: 0,
+            floor: None,
+            history: VecDeque::new(),
+            subscribers: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, beat: u64, log: &Vec<EventLog>) {
+        self.beat = beat;
+
+        while let Some((b, _, _)) = self.history.front() {
+            if *b + EVENT_HISTORY > beat { break }
+            self.floor = Some(*b);
+            self.history.pop_front();
+        }
+
+        if log.is_empty() { return }
+
+        let events = BeatEvents { beat: beat, events: log.clone() };
+        let json = event_chunk(Encoding::Json, &events);
+        let msgpack = event_chunk(Encoding::MsgPack, &events);
+
+        self.subscribers.retain(|(enc, s)| {
+            let chunk = match enc {
+                Encoding::Json => &json,
+                Encoding::MsgPack => &msgpack,
+            };
+            s.try_send(Arc::clone(chunk)).is_ok()
+        });
+        self.history.push_back((beat, json, msgpack));
+    }
+
+    pub fn subscribe(&mut self, since: Option<u64>, enc: Encoding)
+        -> Result<Receiver<Arc<Vec<u8>>>, SubscribeError> {
+
+        let backlog : Vec<&Arc<Vec<u8>>> = match since {
+            None => Vec::new(),
+            Some(since) => {
+                if since > self.beat {
+                    return Err(SubscribeError::Future(self.beat));
+                }
+                if let Some(floor) = self.floor {
+                    if since < floor {
+                        return Err(SubscribeError::Expired(floor));
+                    }
+                }
+                self.history.iter().filter(|(b, _, _)| *b > since)
+                                   .map(|(_, json, msgpack)| match enc {
+                                       Encoding::Json => json,
+                                       Encoding::MsgPack => msgpack,
+                                   })
+                                   .collect()
+            }
+        };
+
+        let (snd, rcv) = bounded(backlog.len() + EVENT_BUFFER);
+        for chunk in backlog {
+            snd.send(Arc::clone(chunk)).expect("Sender::send failed");
+        }
+        self.subscribers.push((enc, snd));
+        Ok(rcv)
+    }
+}
+
+// tiny_http buffers chunked responses, so the event stream takes over the
+// socket and writes a close-delimited body, flushing after every event.
+struct EventStream(Receiver<Arc<Vec<u8>>>, Encoding);
+
+impl Upgrade for EventStream {
+    fn build(&mut self, mut socket: Box<dyn ReadWrite + Send>) {
+        loop {
+            let chunk = match self.0.recv_timeout(EVENT_KEEPALIVE) {
+                Ok(chunk) => chunk,
+                Err(RecvTimeoutError::Timeout) => keepalive_chunk(self.1),
+                Err(RecvTimeoutError::Disconnected) => return,
+            };
+            if socket.write_all(&chunk).and_then(|_| socket.flush()).is_err() {
+                debug!("Event stream closed.");
+                return
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum FungeRequest {
-    StartProcess(Option<String>, String, Responder<Result<u64,String>>),
-    GetState(Option<u64>, Responder<Option<Arc<Vec<u8>>>>),
-    Kill(KillReq)
+    StartProcess(NewProcessReq, Responder<Result<u64,ParseError>>),
+    // The beat the client last saw, and whether it takes a StateUpdate.
+    GetState(Option<u64>, bool, Encoding,
+             Responder<Option<Arc<Vec<u8>>>>),
+    GetProcess(u64, Responder<Option<ProcDetail>>),
+    GetInfo(Responder<ServerInfo>),
+    Subscribe(Option<u64>, Encoding,
+              Responder<Result<Receiver<Arc<Vec<u8>>>, SubscribeError>>),
+    Kill(KillReq),
+    Send(u8, Vec<u8>, Responder<bool>), // false if the channel is full
+    Receive(u8, Responder<Option<Vec<u8>>>),
+    SetTempo(f64, Responder<Option<f64>>),
+    SetRunning(bool, Responder<Option<bool>>),
+    Tap(Instant, Responder<Option<f64>>)
 }
 
 unsafe impl Send for FungeRequest {}
@@ -84,59 +243,389 @@ fn kill(sender: &Sender<FungeRequest>, request: &Request) -> Response {
     Response::json(&KillResp { })
 }
 
+fn channel_send(sender: &Sender<FungeRequest>, ch: u8, request: &Request)
+    -> Response {
+    let data: ChannelSendReq =
+        try_or_400!(rouille::input::json_input(&request));
+    if data.values.is_empty() {
+        return Response::text("No values to send.").with_status_code(400);
+    }
+
+    let responder = Responder::new();
+    sender.send(FungeRequest::Send(ch, data.values, responder.clone()))
+          .expect("Sender::send failed");
+
+    match responder.wait() {
+        Some(true) => Response::json(&ChannelSendResp { }),
+        Some(false) => Response::text(format!("Channel {} is full.", ch))
+            .with_status_code(429),
+        None => Response::text("Server timed out.").with_status_code(503),
+    }
+}
+
+fn channel_read(sender: &Sender<FungeRequest>, ch: u8) -> Response {
+    let responder = Responder::new();
+    sender.send(FungeRequest::Receive(ch, responder.clone()))
+          .expect("Sender::send failed");
+
+    match responder.wait() {
+        Some(Some(values)) => Response::json(&ChannelReadResp { values }),
+        Some(None) => Response::text(format!("Channel {} is not outbound.", ch))
+            .with_status_code(400),
+        None => Response::text("Server timed out.").with_status_code(503),
+    }
+}
+
+// Tempo requests answer with the new tempo, or None when the beats come
+// from an external clock.
+fn tempo_response(responder: Responder<Option<f64>>) -> Response {
+    match responder.wait() {
+        Some(Some(bpm)) => Response::json(&TempoResp { bpm: bpm }),
+        Some(None) => Response::text("Not running on the internal clock.")
+            .with_status_code(409),
+        None => Response::text("Server timed out.").with_status_code(503),
+    }
+}
+
+fn set_tempo(sender: &Sender<FungeRequest>, request: &Request) -> Response {
+    let data: TempoReq = try_or_400!(rouille::input::json_input(&request));
+    let bpm = match clamp_bpm(data.bpm) {
+        Some(bpm) => bpm,
+        None => return Response::text("Tempo must be positive.")
+            .with_status_code(400),
+    };
+
+    let responder = Responder::new();
+    sender.send(FungeRequest::SetTempo(bpm, responder.clone()))
+          .expect("Sender::send failed");
+    tempo_response(responder)
+}
+
+fn tap_tempo(sender: &Sender<FungeRequest>) -> Response {
+    let responder = Responder::new();
+    sender.send(FungeRequest::Tap(Instant::now(), responder.clone()))
+          .expect("Sender::send failed");
+    tempo_response(responder)
+}
+
+fn transport(sender: &Sender<FungeRequest>, request: &Request) -> Response {
+    let data: TransportReq =
+        try_or_400!(rouille::input::json_input(&request));
+
+    let responder = Responder::new();
+    sender.send(FungeRequest::SetRunning(data.running, responder.clone()))
+          .expect("Sender::send failed");
+
+    match responder.wait() {
+        Some(Some(running)) =>
+            Response::json(&TransportResp { running: running }),
+        Some(None) => Response::text("Not running on the internal clock.")
+            .with_status_code(409),
+        None => Response::text("Server timed out.").with_status_code(503),
+    }
+}
+
 fn new_process(sender: &Sender<FungeRequest>, request: &Request) -> Response {
     let data: NewProcessReq = try_or_400!(rouille::input::json_input(&request));
 
     let responder = Responder::new();
-    sender.send(FungeRequest::StartProcess(data.name, data.program,
-                                           responder.clone()))
+    sender.send(FungeRequest::StartProcess(data, responder.clone()))
           .expect("Sender::send failed");
 
     match responder.wait() {
         None => Response::text("Server timed out.").with_status_code(503),
         Some(Ok(resp)) => Response::json(&NewProcessResp { pid: resp }),
-        Some(Err(e)) => Response::text(format!("Bad Program: {}", e))
+        Some(Err(e)) => Response::json(&ProgramErrorResp::from(e))
             .with_status_code(400),
     }
 }
 
+// GET /state
+//   ?prev=N   waits for a beat after N and returns the whole EngineState.
+//   ?delta=N  waits the same way but returns a StateUpdate, which is a
+//             delta from beat N while funged still has that beat's state
+//             (the last 32 beats), and otherwise the whole state.
+// Deltas were asked for on prev=N, but clients written before them read
+// an EngineState from it, so they moved to their own parameter. Both
+// answer again with the same beat if the clock or song position changes
+// while the beat stands still.
 fn get_state(sender: &Sender<FungeRequest>, request: &Request) -> Response {
-    let prev = request.get_param("prev")
-                      .and_then(|p| p.parse::<u64>().ok());
+    let beat = |name| request.get_param(name)
+                             .and_then(|p| p.parse::<u64>().ok());
+    let (prev, delta) = match beat("delta") {
+        Some(b) => (Some(b), true),
+        None => (beat("prev"), false),
+    };
+    let enc = Encoding::from_accept(request.header("Accept"));
 
     let responder = Responder::new();
-    sender.send(FungeRequest::GetState(prev, responder.clone()))
+    sender.send(FungeRequest::GetState(prev, delta, enc, responder.clone()))
           .expect("Sender::send failed");
 
     match responder.wait() {
         Some(Some(bytes)) =>
-            Response::from_data("application/json; charset=utf-8",
-                               (*bytes).clone()),
+            Response::from_data(enc.content_type(), (*bytes).clone()),
         Some(None) => Response::empty_400(),
         None => Response::text("Server timed out.").with_status_code(503),
     }
 }
 
-fn handle_request(sender: &Sender<FungeRequest>, request: &Request)
-    -> Response {
+fn get_process(sender: &Sender<FungeRequest>, pid: u64) -> Response {
+    let responder = Responder::new();
+    sender.send(FungeRequest::GetProcess(pid, responder.clone()))
+          .expect("Sender::send failed");
+
+    match responder.wait() {
+        Some(Some(detail)) => Response::json(&detail),
+        Some(None) => Response::empty_404(),
+        None => Response::text("Server timed out.").with_status_code(503),
+    }
+}
+
+fn get_info(sender: &Sender<FungeRequest>) -> Response {
+    let responder = Responder::new();
+    sender.send(FungeRequest::GetInfo(responder.clone()))
+          .expect("Sender::send failed");
+
+    match responder.wait() {
+        Some(info) => Response::json(&info),
+        None => Response::text("Server timed out.").with_status_code(503),
+    }
+}
+
+fn get_events(sender: &Sender<FungeRequest>, request: &Request) -> Response {
+    let since = match request.get_param("since") {
+        None => None,
+        Some(p) => Some(try_or_400!(p.parse::<u64>())),
+    };
+
+    let enc = Encoding::from_accept(request.header("Accept"));
+    let content_type = match enc {
+        Encoding::Json => "text/event-stream",
+        Encoding::MsgPack => MSGPACK_MIME,
+    };
+
+    let responder = Responder::new();
+    sender.send(FungeRequest::Subscribe(since, enc, responder.clone()))
+          .expect("Sender::send failed");
+
+    match responder.wait() {
+        Some(Ok(channel)) => Response {
+            status_code: 200,
+            headers: vec![
+                ("Content-Type".into(), content_type.into()),
+                ("Cache-Control".into(), "no-cache".into()),
+                ("Upgrade".into(), "event-stream".into())],
+            data: ResponseBody::empty(),
+            upgrade: Some(Box::new(EventStream(channel, enc))),
+        },
+        Some(Err(SubscribeError::Expired(floor))) =>
+            Response::text(format!("Events before beat {} are gone.", floor))
+                .with_status_code(410),
+        Some(Err(SubscribeError::Future(beat))) =>
+            Response::text(format!("Current beat is {}.", beat))
+                .with_status_code(400),
+        None => Response::text("Server timed out.").with_status_code(503),
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Scope {
+    Read,
+    Control
+}
+
+// Optional bearer tokens. Reads are open unless there is a read token, and
+// the control token also grants them. Control needs the control token, or
+// the read token when that is the only one set.
+#[derive(Debug, Clone)]
+struct Auth {
+    read: Option<String>,
+    control: Option<String>
+}
+
+// Compare without bailing out at the first differing byte.
+pub fn token_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len() &&
+        a.bytes().zip(b.bytes()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+impl Auth {
+    fn new(conf: &FungedConfig) -> Self {
+        Auth { read: conf.read_token.clone(),
+               control: conf.control_token.clone() }
+    }
+
+    // The token that grants control, if control is closed.
+    fn control_token(&self) -> Option<&String> {
+        self.control.as_ref().or(self.read.as_ref())
+    }
+
+    // Reads that don't change anything need the read scope. Everything else,
+    // including draining an outbound channel, needs control.
+    fn scope(request: &Request) -> Scope {
+        if request.method() == "GET" && !request.url().starts_with("/channel") {
+            Scope::Read
+        } else {
+            Scope::Control
+        }
+    }
+
+    fn allows(&self, request: &Request) -> bool {
+        let token = request.header("Authorization")
+                           .and_then(|h| h.strip_prefix("Bearer "))
+                           .map(|t| t.trim());
+        let matches = |expected: Option<&String>| match (expected, token) {
+            (Some(e), Some(t)) => token_eq(e, t),
+            _ => false,
+        };
+        match Auth::scope(request) {
+            Scope::Control => self.control_token().is_none() ||
+                matches(self.control_token()),
+            Scope::Read => self.read.is_none() ||
+                matches(self.read.as_ref()) ||
+                matches(self.control.as_ref()),
+        }
+    }
+}
+
+fn handle_request(sender: &Sender<FungeRequest>, auth: &Auth,
+                  request: &Request) -> Response {
+    if !auth.allows(request) {
+        return Response::text("Unauthorized.")
+            .with_status_code(401)
+            .with_additional_header("WWW-Authenticate", "Bearer");
+    }
+
     router!(request,
         (GET) (/state) => { get_state(sender, request) },
+        (GET) (/process/{pid: u64}) => { get_process(sender, pid) },
+        (GET) (/events) => { get_events(sender, request) },
+        (GET) (/info) => { get_info(sender) },
         (POST) (/process) => { new_process(sender, request) },
         (POST) (/kill) => { kill(sender, request) },
+        (POST) (/tempo) => { set_tempo(sender, request) },
+        (POST) (/tempo/tap) => { tap_tempo(sender) },
+        (POST) (/transport) => { transport(sender, request) },
+        (GET) (/channel/{ch: u8}) => { channel_read(sender, ch) },
+        (POST) (/channel/{ch: u8}) => { channel_send(sender, ch, request) },
 
         _ => Response::empty_404()
     )
 }
 
+// The socket is bound in a directory only we can enter and moved into place
+// once it's accessible only to its owner, so nobody else can connect in
+// between.
+fn bind_unix(path: &str) -> tiny_http::Server {
+    match fs::symlink_metadata(path) {
+        Ok(m) if m.file_type().is_socket() =>
+            fs::remove_file(path).expect(
+                &format!("Failed to remove old socket {}", path)),
+        Ok(_) => panic!("{} exists and is not a socket", path),
+        Err(e) if e.kind() != ErrorKind::NotFound =>
+            panic!("Failed to stat {}: {:?}", path, e),
+        Err(_) => {}
+    }
+    let dir = format!("{}.{}", path, process::id());
+    fs::DirBuilder::new().mode(0o700).create(&dir)
+        .expect(&format!("Failed to create {}", dir));
+    let tmp = Path::new(&dir).join("socket");
+    let server = tiny_http::Server::http_unix(&tmp)
+        .expect(&format!("Failed to listen on {}", path));
+    fs::set_permissions(&tmp, fs::Permissions::from_mode(0o600))
+        .expect(&format!("Failed to set permissions on {}", path));
+    fs::rename(&tmp, path).expect(&format!("Failed to move socket to {}",
+                                           path));
+    fs::remove_dir(&dir).expect(&format!("Failed to remove {}", dir));
+    server
+}
+
+fn handle_unix(mut trq: tiny_http::Request, sender: &Sender<FungeRequest>,
+               auth: &Auth) {
+    let local : SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let mut data = Vec::new();
+    if trq.as_reader().read_to_end(&mut data).is_err() { return }
+    let headers = trq.headers().iter()
+        .map(|h| (h.field.to_string(), h.value.to_string()))
+        .collect();
+    let request = Request::fake_http_from(local, trq.method().as_str(),
+                                          trq.url(), headers, data);
+
+    let response = handle_request(sender, auth, &request);
+    let (body, len) = response.data.into_reader_and_size();
+    let mut tresp = tiny_http::Response::empty(response.status_code)
+        .with_data(body, len);
+    let mut upgrade = String::new();
+    for (key, value) in response.headers {
+        if key.eq_ignore_ascii_case("Content-Length") {
+            continue;
+        }
+        if key.eq_ignore_ascii_case("Upgrade") {
+            upgrade = value.into();
+            continue;
+        }
+        if let Ok(h) = tiny_http::Header::from_bytes(key.as_bytes(),
+                                                     value.as_bytes()) {
+            tresp.add_header(h);
+        }
+    }
+    match response.upgrade {
+        Some(mut up) => up.build(trq.upgrade(&upgrade, tresp)),
+        None => { let _ = trq.respond(tresp); }
+    }
+}
+
+// rouille only listens on TCP, so requests on a Unix socket are read with
+// tiny_http and passed through the same handler, on the same number of
+// workers. The socket is only accessible to its owner.
+fn serve_unix(path: &str, sender: Sender<FungeRequest>, auth: Auth) {
+    let server = bind_unix(path);
+    let (queue, requests) = bounded(UNIX_BACKLOG);
+    for _ in 0..HTTP_WORKERS {
+        let requests: Receiver<tiny_http::Request> = requests.clone();
+        let sender = sender.clone();
+        let auth = auth.clone();
+        thread::spawn(move || {
+            for trq in requests.iter() {
+                handle_unix(trq, &sender, &auth);
+            }
+        });
+    }
+
+    for trq in server.incoming_requests() {
+        if let Err(TrySendError::Full(trq)) = queue.try_send(trq) {
+            let resp = tiny_http::Response::from_string("Server busy.")
+                .with_status_code(503);
+            let _ = trq.respond(resp);
+        }
+    }
+}
+
 impl ServerHandle {
 
     pub fn new(conf: &FungedConfig) -> ServerHandle {
         let (snd, rcv) = bounded(4);
+        let auth = Auth::new(conf);
+
+        // OSC messages carry the control token as their first argument.
+        if let Some(port) = conf.osc_port {
+            let addr = format!("{}:{}", conf.osc_host, port);
+            let udp = UdpSocket::bind(&addr)
+                .expect(&format!("Failed to bind OSC port {}", addr));
+            osc::serve(udp, conf.osc_dir.clone(),
+                       auth.control_token().cloned(), snd.clone());
+        }
 
         let host = format!("{}:{}", conf.host, conf.port);
+        let socket = conf.socket.clone();
         let handle = thread::spawn(move || {
-            rouille::start_server(host, move |request|
-                handle_request(&snd.clone(), request));
+            match socket {
+                Some(path) => serve_unix(&path, snd, auth),
+                None => rouille::start_server_with_pool(
+                    host, Some(HTTP_WORKERS), move |request|
+                        handle_request(&snd.clone(), &auth, request)),
+            }
         });
 
         ServerHandle { thread: handle,
@@ -148,3 +637,111 @@ impl ServerHandle {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_backlog() {
+        let mut events = EventBroadcast::new();
+        events.push(1, &vec![EventLog::PrintNum(1, 1)]);
+        events.push(2, &vec![]);
+        events.push(3, &vec![EventLog::PrintNum(1, 3)]);
+
+        let rcv = events.subscribe(Some(1), Encoding::Json).unwrap();
+        let chunk = rcv.try_recv().unwrap();
+        assert!(chunk.starts_with(b"id: 3\n"));
+        assert!(rcv.try_recv().is_err());
+
+        events.push(4, &vec![EventLog::Finished(1)]);
+        assert!(rcv.try_recv().unwrap().starts_with(b"id: 4\n"));
+
+        match events.subscribe(Some(5), Encoding::Json) {
+            Err(SubscribeError::Future(4)) => {},
+            r => panic!("Unexpected subscribe result: {:?}", r),
+        }
+
+        events.push(EVENT_HISTORY + 2, &vec![]);
+        match events.subscribe(Some(0), Encoding::Json) {
+            Err(SubscribeError::Expired(1)) => {},
+            r => panic!("Unexpected subscribe result: {:?}", r),
+        }
+        assert!(events.subscribe(Some(1), Encoding::Json).is_ok());
+    }
+
+    #[test]
+    fn auth_scopes() {
+        let get = |url: &str, token: Option<&str>| {
+            let headers = token.map(|t| vec![("Authorization".to_string(),
+                                              format!("Bearer {}", t))])
+                               .unwrap_or_default();
+            Request::fake_http("GET", url, headers, Vec::new())
+        };
+        let post = |url: &str, token: &str| {
+            Request::fake_http("POST", url,
+                               vec![("Authorization".to_string(),
+                                     format!("Bearer {}", token))],
+                               Vec::new())
+        };
+
+        let auth = Auth { read: None, control: Some("ctl".to_string()) };
+        assert!(auth.allows(&get("/state", None)));
+        assert!(!auth.allows(&get("/channel/1", None)));
+        assert!(!auth.allows(&post("/kill", "nope")));
+        assert!(auth.allows(&post("/kill", "ctl")));
+
+        let auth = Auth { read: Some("rd".to_string()),
+                          control: Some("ctl".to_string()) };
+        assert!(!auth.allows(&get("/state", None)));
+        assert!(auth.allows(&get("/state", Some("rd"))));
+        assert!(auth.allows(&get("/events", Some("ctl"))));
+        assert!(!auth.allows(&post("/process", "rd")));
+
+        // The read token alone closes control too.
+        let auth = Auth { read: Some("rd".to_string()), control: None };
+        assert!(!auth.allows(&get("/state", None)));
+        assert!(!auth.allows(&post("/kill", "nope")));
+        assert!(auth.allows(&post("/kill", "rd")));
+    }
+
+    #[test]
+    fn event_msgpack() {
+        let mut events = EventBroadcast::new();
+        let rcv = events.subscribe(None, Encoding::MsgPack).unwrap();
+        events.push(1, &vec![EventLog::PrintNum(1, 1)]);
+
+        let chunk = rcv.try_recv().unwrap();
+        let evs : Option<BeatEvents> =
+            Encoding::MsgPack.decode(&chunk).unwrap();
+        assert_eq!(evs.map(|e| e.beat), Some(1));
+        let evs : Option<BeatEvents> =
+            Encoding::MsgPack.decode(&keepalive_chunk(Encoding::MsgPack))
+                             .unwrap();
+        assert!(evs.is_none());
+    }
+
+    #[test]
+    fn unix_socket() {
+        use std::os::unix::net::UnixStream;
+        let path = std::env::temp_dir().join("funged_test.sock");
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+        fs::write(path, "").unwrap();
+        let bound = std::panic::catch_unwind(|| bind_unix(path));
+        assert!(bound.is_err());
+        fs::remove_file(path).unwrap();
+
+        let _server = bind_unix(path);
+        let meta = fs::metadata(path).unwrap();
+        assert!(meta.file_type().is_socket());
+        assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+        assert!(!Path::new(&format!("{}.{}", path, process::id())).exists());
+        assert!(UnixStream::connect(path).is_ok());
+
+        // A stale socket is replaced.
+        let _server = bind_unix(path);
+        assert!(UnixStream::connect(path).is_ok());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,212 @@
+/*
+    Noisefunge Copyright (C) 2021 Rev. Johnny Healey <rev.null@gmail.com>
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Standard MIDI File output, so a performance can be rendered offline. Each
+// engine channel gets its own track, after a conductor track with the tempo.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Write};
+use crate::config::ChannelConfig;
+use crate::jack::MidiMsg;
+use crate::midi_bridge::MidiSink;
+
+// One tick per MIDI clock, which is what the engine counts in.
+pub const TICKS_PER_QUARTER: u16 = 24;
+
+pub struct SmfSink {
+    period: u64,
+    midi_channels: [u8; 256],
+    tracks: RefCell<BTreeMap<u8, Vec<(u64, Vec<u8>)>>>
+}
+
+fn write_varlen(out: &mut Vec<u8>, mut n: u64) {
+    let mut bytes = vec![(n & 0x7f) as u8];
+    n >>= 7;
+    while n > 0 {
+        bytes.push(0x80 | (n & 0x7f) as u8);
+        n >>= 7;
+    }
+    bytes.reverse();
+    out.extend(bytes);
+}
+
+fn write_track<W: Write>(out: &mut W, events: &[(u64, Vec<u8>)], end: u64)
+    -> io::Result<()> {
+    let mut data = Vec::new();
+    let mut last = 0;
+    for (tick, bytes) in events {
+        write_varlen(&mut data, tick - last);
+        data.extend(bytes);
+        last = *tick;
+    }
+    write_varlen(&mut data, end.saturating_sub(last));
+    data.extend(&[0xff, 0x2f, 0x00]);
+
+    out.write_all(b"MTrk")?;
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(&data)
+}
+
+impl SmfSink {
+    // Channels are mapped to MIDI channels the same way as the JACK ports,
+    // and start with their configured bank, program and pan. Period is the
+    // number of clock ticks in an engine beat.
+    pub fn new(channels: &[Option<ChannelConfig>], period: u64) -> Self {
+        let mut midi_channels = [0; 256];
+        for (i, ch) in midi_channels.iter_mut().enumerate() {
+            *ch = (i % 16) as u8;
+        }
+        let mut sink = SmfSink { period: period,
+                                 midi_channels: midi_channels,
+                                 tracks: RefCell::new(BTreeMap::new()) };
+
+        for (i, cc) in channels.iter().enumerate() {
+            let cc = match cc {
+                Some(cc) => cc,
+                None => continue
+            };
+            sink.midi_channels[i] = cc.midi_channel(i as u8);
+            if cc.bank.is_some() || cc.program.is_some() {
+                let msg = MidiMsg::Program(i as u8, cc.bank, cc.program);
+                sink.send_midi(0, msg);
+            }
+            if let Some(pan) = cc.pan {
+                sink.send_midi(0, MidiMsg::Pan(i as u8, pan));
+            }
+        }
+        sink
+    }
+
+    // Write a format 1 file ending at tick end. Notes still sounding are
+    // released there.
+    pub fn write<W: Write>(&self, out: &mut W, bpm: f64, end: u64)
+        -> io::Result<()> {
+        let tracks = self.tracks.borrow();
+
+        out.write_all(b"MThd")?;
+        out.write_all(&6u32.to_be_bytes())?;
+        out.write_all(&1u16.to_be_bytes())?;
+        out.write_all(&(tracks.len() as u16 + 1).to_be_bytes())?;
+        out.write_all(&TICKS_PER_QUARTER.to_be_bytes())?;
+
+        let usec = (60_000_000.0 / bpm).round() as u32;
+        let tempo = usec.to_be_bytes();
+        write_track(out, &[(0, vec![0xff, 0x51, 0x03,
+                                    tempo[1], tempo[2], tempo[3]])], end)?;
+
+        for (ch, events) in tracks.iter() {
+            let mut events = events.clone();
+            let mut name = vec![0xff, 0x03];
+            let text = format!("noisefunge {}", ch);
+            write_varlen(&mut name, text.len() as u64);
+            name.extend(text.as_bytes());
+            events.insert(0, (0, name));
+
+            let mut held = BTreeSet::new();
+            for (_, bytes) in &events {
+                match bytes[0] & 0xf0 {
+                    0x90 => { held.insert((bytes[0] & 0x0f, bytes[1])); },
+                    0x80 => { held.remove(&(bytes[0] & 0x0f, bytes[1])); },
+                    _ => {}
+                }
+            }
+            let end = events.last().map(|e| e.0).unwrap_or(0).max(end);
+            for (mch, pch) in held {
+                events.push((end, vec![0x80 + mch, pch, 0]));
+            }
+
+            write_track(out, &events, end)?;
+        }
+        Ok(())
+    }
+}
+
+impl MidiSink for SmfSink {
+    fn send_midi(&self, beat: u64, msg: MidiMsg) -> bool {
+        let (ch, bytes) = match msg {
+            MidiMsg::On(ch, pch, vel) => {
+                let mch = self.midi_channels[ch as usize];
+                (ch, vec![vec![0x90 + mch, pch, vel]])
+            },
+            MidiMsg::Off(ch, pch) => {
+                let mch = self.midi_channels[ch as usize];
+                (ch, vec![vec![0x80 + mch, pch, 0]])
+            },
+            MidiMsg::Program(ch, bank, patch) => {
+                let mch = self.midi_channels[ch as usize];
+                let mut v = Vec::new();
+                if let Some(bank) = bank {
+                    v.push(vec![0xb0 + mch, 0, (bank >> 7) as u8]);
+                    v.push(vec![0xb0 + mch, 32, (bank & 127) as u8]);
+                }
+                if let Some(patch) = patch {
+                    v.push(vec![0xc0 + mch, patch]);
+                }
+                (ch, v)
+            },
+            MidiMsg::Pan(ch, pan) => {
+                let mch = self.midi_channels[ch as usize];
+                (ch, vec![vec![0xb0 + mch, 10, pan & 127]])
+            },
+            MidiMsg::AllOff(ch) => {
+                let mch = self.midi_channels[ch as usize];
+                (ch, vec![vec![0xb0 + mch, 123, 0]])
+            },
+        };
+        let tick = beat * self.period;
+        let mut tracks = self.tracks.borrow_mut();
+        let track = tracks.entry(ch).or_insert_with(Vec::new);
+        track.extend(bytes.into_iter().map(|b| (tick, b)));
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn smf_bytes() {
+        let sink = SmfSink::new(&[], 8);
+        sink.send_midi(0, MidiMsg::On(17, 60, 100));
+        sink.send_midi(25, MidiMsg::Off(17, 60));
+        sink.send_midi(25, MidiMsg::On(17, 62, 90));
+
+        let mut out = Vec::new();
+        sink.write(&mut out, 120.0, 240).unwrap();
+
+        assert_eq!(&out[..14], &[b'M', b'T', b'h', b'd', 0, 0, 0, 6,
+                                 0, 1, 0, 2, 0, 24]);
+        let conductor = [b'M', b'T', b'r', b'k', 0, 0, 0, 12,
+                         0, 0xff, 0x51, 0x03, 0x07, 0xa1, 0x20,
+                         0x81, 0x70, 0xff, 0x2f, 0x00];
+        assert_eq!(&out[14..34], &conductor);
+
+        let name = b"noisefunge 17";
+        let mut track = vec![0, 0xff, 0x03, name.len() as u8];
+        track.extend(name);
+        track.extend(&[0, 0x91, 60, 100,
+                       0x81, 0x48, 0x81, 60, 0,
+                       0, 0x91, 62, 90,
+                       0x28, 0x81, 62, 0,
+                       0, 0xff, 0x2f, 0x00]);
+        assert_eq!(&out[34..38], b"MTrk");
+        assert_eq!(&out[38..42], &(track.len() as u32).to_be_bytes());
+        assert_eq!(&out[42..], &track[..]);
+    }
+}
@@ -23,7 +23,7 @@ use std::cmp;
 use std::mem;
 use std::time::Duration;
 
-fn read_args() -> String {
+fn read_args() -> Endpoint {
     let matches = App::new("nfbuffer")
                           .arg(Arg::with_name("HOST")
                                .help("Noisefunge server host")
@@ -35,15 +35,22 @@ fn read_args() -> String {
                                .required(false)
                                .env("NOISEFUNGE_PORT")
                                .default_value("1312"))
+                          .arg(Arg::with_name("SOCKET")
+                               .long("socket")
+                               .help("Noisefunge server Unix socket")
+                               .takes_value(true)
+                               .required(false)
+                               .env("NOISEFUNGE_SOCKET"))
                           .get_matches();
 
-    format!("http://{}:{}/", matches.value_of("HOST").unwrap(),
-                             matches.value_of("PORT").unwrap())
+    Endpoint::new(matches.value_of("HOST").unwrap(),
+                  matches.value_of("PORT").unwrap(),
+                  matches.value_of("SOCKET"))
 }
 
 fn main() {
 
-    let baseuri = read_args();
+    let endpoint = read_args();
 
     let window = initscr();
     cbreak();
@@ -59,7 +66,7 @@ fn main() {
     }
 
     window.nodelay(true);
-    let client = FungeClient::new(&baseuri);
+    let client = FungeClient::new(&endpoint);
     let sleep_dur = Duration::from_millis(10);
     let mut state : Option<EngineState> = None;
     let mut err = None;
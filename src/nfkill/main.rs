@@ -17,11 +17,9 @@
 
 use clap::{Arg, App};
 use noisefunge::api::*;
-use reqwest::blocking::Client;
 use std::time::Duration;
-use serde_json;
 
-fn read_args() -> (KillReq, String) {
+fn read_args() -> (KillReq, Endpoint) {
     let matches = App::new("nfkill")
                           .arg(Arg::with_name("PID_OR_NAME")
                                .help("PID OR NAME of process to kill, in hex.")
@@ -53,13 +51,20 @@ fn read_args() -> (KillReq, String) {
                                .required(false)
                                .env("NOISEFUNGE_PORT")
                                .default_value("1312"))
+                          .arg(Arg::with_name("SOCKET")
+                               .long("socket")
+                               .help("Noisefunge server Unix socket")
+                               .takes_value(true)
+                               .required(false)
+                               .env("NOISEFUNGE_SOCKET"))
                           .get_matches();
 
-    let baseuri = format!("http://{}:{}/", matches.value_of("HOST").unwrap(),
-                                           matches.value_of("PORT").unwrap());
+    let endpoint = Endpoint::new(matches.value_of("HOST").unwrap(),
+                                 matches.value_of("PORT").unwrap(),
+                                 matches.value_of("SOCKET"));
 
     if matches.is_present("ALL") {
-        return (KillReq::All, baseuri)
+        return (KillReq::All, endpoint)
     }
 
     if matches.is_present("NAME") {
@@ -67,7 +72,7 @@ fn read_args() -> (KillReq, String) {
         for name in matches.values_of("PID_OR_NAME").unwrap() {
             names.push(name.to_string());
         }
-        return (KillReq::Names(names), baseuri);
+        return (KillReq::Names(names), endpoint);
     }
 
     let mut pids = Vec::new();
@@ -77,38 +82,27 @@ fn read_args() -> (KillReq, String) {
         pids.push(parsed);
     }
 
-    return (KillReq::Pids(pids), baseuri)
+    return (KillReq::Pids(pids), endpoint)
 }
 
 fn main() {
 
-    let (req, baseuri) = read_args();
-
-    let client = Client::builder().user_agent("nfkill")
-                                  .build()
-                                  .expect("Failed to build client.");
+    let (req, endpoint) = read_args();
 
-    let body = serde_json::to_string(&req).unwrap();
-    let path = format!("{}kill", baseuri);
-    let request = client.post(&path)
-                        .body(body)
-                        .header("Content-Type", "application/json")
-                        .timeout(Duration::from_secs(4))
-                        .build()
-                        .expect("Failed to build request");
+    let client = ApiClient::new(&endpoint, "nfkill");
 
-    let response = client.execute(request);
+    let response = client.post("kill", &req, Some(Duration::from_secs(4)));
     std::process::exit(match response {
         Ok(response) => {
-            if response.status().is_success() {
+            if response.is_success() {
                 0
             } else {
-                eprintln!("Error response: {:?}", response.status());
+                eprintln!("Error response: {}", response.status);
                 1
             }
         }, 
         Err(err) => {
-            eprintln!("Failed: {:?}", err);
+            eprintln!("Failed: {}", err);
             1
         }
     });
@@ -0,0 +1,119 @@
+/*
+    Noisefunge Copyright (C) 2021 Rev. Johnny Healey <rev.null@gmail.com>
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use clap::{Arg, App};
+use noisefunge::befunge::{CharMap, OpSet, PC, Prog, SourceMode};
+use noisefunge::befunge::lint::{analyze, Reach};
+use std::fs;
+
+fn read_args() -> (String, SourceMode, bool) {
+    let matches = App::new("nflint")
+                          .arg(Arg::with_name("FILE")
+                               .help("File containing noisefunge program.")
+                               .required(true))
+                          .arg(Arg::with_name("CHARMAP")
+                               .short("c")
+                               .long("charmap")
+                               .help("Program is written in charmap glyphs")
+                               .takes_value(false)
+                               .required(false))
+                          .arg(Arg::with_name("QUIET")
+                               .short("q")
+                               .long("quiet")
+                               .help("Only print problems")
+                               .takes_value(false)
+                               .required(false))
+                          .get_matches();
+
+    let mode = if matches.is_present("CHARMAP") {
+        SourceMode::Charmap
+    } else {
+        SourceMode::Raw
+    };
+
+    (String::from(matches.value_of("FILE").unwrap()), mode,
+     matches.is_present("QUIET"))
+}
+
+fn main() {
+    let (filename, mode, quiet) = read_args();
+
+    let err = format!("Failed to open {}", &filename);
+    let src = fs::read_to_string(&filename).expect(&err);
+    let src = src.trim_end_matches('\n');
+
+    let cm = CharMap::default();
+    let prog = match Prog::parse_mode(src, mode, &cm) {
+        Ok(prog) => prog,
+        Err(e) => {
+            match e.position() {
+                Some((line, col)) =>
+                    eprintln!("{}:{}:{}: error: {}", filename, line, col, e),
+                None => eprintln!("{}: error: {}", filename, e),
+            }
+            std::process::exit(1);
+        }
+    };
+
+    let ops = OpSet::default();
+    let analysis = analyze(&prog, &ops);
+    let w = prog.cols();
+
+    for (pc, hazard) in &analysis.hazards {
+        println!("{}:{}:{}: warning: {}", filename, pc / w + 1, pc % w + 1,
+                 hazard);
+    }
+    for (pc, limit) in &analysis.limits {
+        println!("{}:{}:{}: note: {}", filename, pc / w + 1, pc % w + 1,
+                 limit);
+    }
+
+    if !quiet {
+        // Mark each cell under the program: ^ for opcodes some path
+        // executes, " for cells only read as quoted data.
+        let glyphs: Vec<char> = (0..w * prog.rows()).map(|i| {
+            let c = prog.lookup(PC(i));
+            match mode {
+                SourceMode::Raw => c as char,
+                SourceMode::Charmap => cm[c],
+            }
+        }).collect();
+        println!();
+        for (row, reach) in glyphs.chunks(w).zip(analysis.reach.chunks(w)) {
+            println!("{}", row.iter().collect::<String>().trim_end());
+            println!("{}", reach.iter().map(|r| match r {
+                Reach::No => ' ',
+                Reach::Op => '^',
+                Reach::Data => '"',
+            }).collect::<String>().trim_end());
+        }
+        println!();
+
+        println!("Reachable opcodes:");
+        for c in analysis.reachable_ops(&prog) {
+            let name = match ops.lookup(c) {
+                Some(op) => op.name.clone(),
+                None if analysis.defined.contains(&c) =>
+                    "(user defined)".to_string(),
+                None => "(undefined)".to_string(),
+            };
+            println!("{:2X} | {:1} | {}", c, cm[c], name);
+        }
+    }
+
+    std::process::exit(if analysis.hazards.is_empty() { 0 } else { 1 });
+}
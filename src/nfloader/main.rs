@@ -18,14 +18,26 @@
 use clap::{Arg, App};
 use std::fs;
 use noisefunge::api::*;
-use reqwest::blocking::Client;
+use noisefunge::befunge::SourceMode;
 use std::time::Duration;
 
-fn read_args() -> (String, String) {
+fn read_args() -> (String, SourceMode, bool, Endpoint) {
     let matches = App::new("nfloader")
                           .arg(Arg::with_name("FILE")
                                .help("File containing noisefunge program.")
                                .required(true))
+                          .arg(Arg::with_name("CHARMAP")
+                               .short("c")
+                               .long("charmap")
+                               .help("Program is written in charmap glyphs")
+                               .takes_value(false)
+                               .required(false))
+                          .arg(Arg::with_name("STRICT")
+                               .short("s")
+                               .long("strict")
+                               .help("Reject tabs, CRLF and undefined opcodes")
+                               .takes_value(false)
+                               .required(false))
                           .arg(Arg::with_name("HOST")
                                .help("Noisefunge server host")
                                .required(false)
@@ -36,50 +48,69 @@ fn read_args() -> (String, String) {
                                .required(false)
                                .env("NOISEFUNGE_PORT")
                                .default_value("1312"))
+                          .arg(Arg::with_name("SOCKET")
+                               .long("socket")
+                               .help("Noisefunge server Unix socket")
+                               .takes_value(true)
+                               .required(false)
+                               .env("NOISEFUNGE_SOCKET"))
                           .get_matches();
 
-    let baseuri = format!("http://{}:{}/", matches.value_of("HOST").unwrap(),
-                                           matches.value_of("PORT").unwrap());
+    let endpoint = Endpoint::new(matches.value_of("HOST").unwrap(),
+                                 matches.value_of("PORT").unwrap(),
+                                 matches.value_of("SOCKET"));
+
+    let mode = if matches.is_present("CHARMAP") {
+        SourceMode::Charmap
+    } else {
+        SourceMode::Raw
+    };
 
-    (String::from(matches.value_of("FILE").unwrap()), baseuri)
+    (String::from(matches.value_of("FILE").unwrap()), mode,
+     matches.is_present("STRICT"), endpoint)
 }
 
 fn main() {
 
-    let (filename, baseuri) = read_args();
+    let (filename, mode, strict, endpoint) = read_args();
 
     let err = format!("Failed to open {}", &filename);
     let prog = fs::read_to_string(&filename).expect(&err)
                                             .trim_end_matches('\n')
                                             .to_string();
 
-    let client = Client::builder().user_agent("nfloader")
-                                  .build()
-                                  .expect("Failed to build client.");
+    let client = ApiClient::new(&endpoint, "nfloader");
 
-    let body = serde_json::to_string(&NewProcessReq { name: Some(filename),
-                                                      program: prog }).unwrap();
-    let path = format!("{}process", baseuri);
-    let request = client.post(&path)
-                        .body(body)
-                        .header("Content-Type", "application/json")
-                        .timeout(Duration::from_secs(4))
-                        .build()
-                        .expect("Failed to build request");
-    let response = client.execute(request);
+    let response = client.post("process",
+                               &NewProcessReq { name: Some(filename.clone()),
+                                                program: prog,
+                                                mode: mode,
+                                                strict: strict },
+                               Some(Duration::from_secs(4)));
     std::process::exit(match response {
         Ok(response) => {
-            if response.status().is_success() {
-                let resp: NewProcessResp = response.json().unwrap();
+            if response.is_success() {
+                let resp: NewProcessResp = response.decode().unwrap();
                 println!("{:X}", resp.pid);
                 0
+            } else if response.status == 400 {
+                match response.decode::<ProgramErrorResp>() {
+                    Ok(ProgramErrorResp { message, line: Some(line),
+                                          col: Some(col) }) =>
+                        eprintln!("{}:{}:{}: error: {}",
+                                  filename, line, col, message),
+                    Ok(ProgramErrorResp { message, .. }) =>
+                        eprintln!("{}: error: {}", filename, message),
+                    Err(_) => eprintln!("Error response: 400"),
+                }
+                1
             } else {
-                eprintln!("Error response: {:?}", response.status());
+                eprintln!("Error response: {}", response.status);
                 1
             }
         }, 
         Err(err) => {
-            eprintln!("Failed: {:?}", err);
+            eprintln!("Failed: {}", err);
             1
         }
     });
@@ -15,18 +15,69 @@
     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-use noisefunge::befunge::{OpSet, CharMap};
+use clap::{Arg, App};
+use noisefunge::api::*;
+use noisefunge::befunge::Engine;
+
+fn read_args() -> (Endpoint, bool) {
+    let matches = App::new("nfops")
+                          .arg(Arg::with_name("HOST")
+                               .help("Noisefunge server host")
+                               .required(false)
+                               .env("NOISEFUNGE_HOST")
+                               .default_value("localhost"))
+                          .arg(Arg::with_name("PORT")
+                               .help("Noisefunge server port")
+                               .required(false)
+                               .env("NOISEFUNGE_PORT")
+                               .default_value("1312"))
+                          .arg(Arg::with_name("SOCKET")
+                               .long("socket")
+                               .help("Noisefunge server Unix socket")
+                               .takes_value(true)
+                               .required(false)
+                               .env("NOISEFUNGE_SOCKET"))
+                          .arg(Arg::with_name("LOCAL")
+                               .short("l")
+                               .long("local")
+                               .help("Show the built in ops without asking \
+                                      the server")
+                               .takes_value(false)
+                               .required(false))
+                          .get_matches();
+
+    (Endpoint::new(matches.value_of("HOST").unwrap(),
+                   matches.value_of("PORT").unwrap(),
+                   matches.value_of("SOCKET")),
+     matches.is_present("LOCAL"))
+}
+
+// The ops and charmap a server would have with no user ops defined.
+fn local_info() -> (Vec<OpInfo>, String) {
+    let engine = Engine::new(24);
+    (engine.ops_info(), engine.charmap().as_string())
+}
 
 fn main() {
-    let charmap = CharMap::default();
-    let opset = OpSet::default();
-
-    for i in 0..=255 {
-        let op = match opset.lookup(i) {
-            None => continue,
-            Some(op) => op
-        };
-        println!("{:2X} | {:1} | {:11} | {}", op.opcode, charmap[op.opcode],
-                 op.name, op.description);
+    let (endpoint, local) = read_args();
+
+    let (ops, charmap) = if local {
+        local_info()
+    } else {
+        match get_info(&ApiClient::new(&endpoint, "nfops")) {
+            Ok(info) => (info.ops, info.charmap),
+            Err(e) => {
+                eprintln!("Failed to get server info ({}), showing built in \
+                           ops.", e);
+                local_info()
+            }
+        }
+    };
+    let charmap : Vec<char> = charmap.chars().collect();
+
+    for op in ops {
+        let c = charmap.get(op.opcode as usize).cloned().unwrap_or(' ');
+        println!("{:2X} | {:1} | {:11} | {}", op.opcode, c, op.name,
+                 op.description);
     }
 }
@@ -0,0 +1,246 @@
+/*
+    Noisefunge Copyright (C) 2021 Rev. Johnny Healey <rev.null@gmail.com>
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use clap::{Arg, App};
+use noisefunge::befunge::*;
+use noisefunge::clock::clamp_bpm;
+use noisefunge::config::FungedConfig;
+use noisefunge::midi_bridge::{MidiBridge, MidiSink, RecordingSink};
+use noisefunge::smf::SmfSink;
+use serde_json::json;
+use std::fs;
+
+struct Args {
+    files: Vec<String>,
+    mode: SourceMode,
+    beats: u64,
+    period: u64,
+    config: Option<String>,
+    json: bool,
+    midi: bool,
+    output: Option<String>,
+    tempo: Option<f64>
+}
+
+fn read_args() -> Args {
+    let matches = App::new("nfrun")
+                          .about("Run programs without JACK or a server")
+                          .arg(Arg::with_name("FILE")
+                               .help("Files containing noisefunge programs.")
+                               .required(true)
+                               .multiple(true))
+                          .arg(Arg::with_name("CHARMAP")
+                               .short("c")
+                               .long("charmap")
+                               .help("Programs are written in charmap glyphs")
+                               .takes_value(false)
+                               .required(false))
+                          .arg(Arg::with_name("BEATS")
+                               .short("n")
+                               .long("beats")
+                               .help("Number of engine beats to run")
+                               .takes_value(true)
+                               .default_value("96"))
+                          .arg(Arg::with_name("PERIOD")
+                               .short("p")
+                               .long("period")
+                               .help("Clock ticks per engine beat")
+                               .takes_value(true)
+                               .default_value("24"))
+                          .arg(Arg::with_name("CONFIG")
+                               .long("config")
+                               .help("funged config to take the period, \
+                                      charmap, outbound channels, preloads \
+                                      and note filters from")
+                               .takes_value(true)
+                               .required(false))
+                          .arg(Arg::with_name("JSON")
+                               .short("j")
+                               .long("json")
+                               .help("Print one JSON object per line")
+                               .takes_value(false)
+                               .required(false))
+                          .arg(Arg::with_name("MIDI")
+                               .short("m")
+                               .long("midi")
+                               .help("Pass notes through the note filters \
+                                      and print the MIDI they produce")
+                               .takes_value(false)
+                               .required(false))
+                          .arg(Arg::with_name("OUTPUT")
+                               .short("o")
+                               .long("output")
+                               .help("Render the filtered MIDI to a \
+                                      Standard MIDI File")
+                               .takes_value(true)
+                               .required(false))
+                          .arg(Arg::with_name("TEMPO")
+                               .short("t")
+                               .long("tempo")
+                               .help("Beats per minute for --output \
+                                      (default: config tempo, or 120)")
+                               .takes_value(true)
+                               .required(false))
+                          .get_matches();
+
+    let mode = if matches.is_present("CHARMAP") {
+        SourceMode::Charmap
+    } else {
+        SourceMode::Raw
+    };
+    let number = |name: &str| {
+        let v = matches.value_of(name).unwrap();
+        v.parse::<u64>().unwrap_or_else(|_| {
+            eprintln!("Bad {}: {}", name.to_lowercase(), v);
+            std::process::exit(2);
+        })
+    };
+    let period = number("PERIOD");
+    if period == 0 || 24 % period != 0 {
+        eprintln!("Period must be one of: 1,2,3,4,6,8,12,24");
+        std::process::exit(2);
+    }
+
+    let tempo = matches.value_of("TEMPO").map(|t| {
+        match t.parse::<f64>().ok().and_then(clamp_bpm) {
+            Some(t) => t,
+            None => {
+                eprintln!("Bad tempo: {}", t);
+                std::process::exit(2);
+            }
+        }
+    });
+
+    Args { files: matches.values_of("FILE").unwrap()
+                         .map(String::from).collect(),
+           mode: mode,
+           beats: number("BEATS"),
+           period: period,
+           config: matches.value_of("CONFIG").map(String::from),
+           json: matches.is_present("JSON"),
+           midi: matches.is_present("MIDI"),
+           output: matches.value_of("OUTPUT").map(String::from),
+           tempo: tempo }
+}
+
+fn load(engine: &mut Engine, filename: &str, mode: SourceMode) {
+    let err = format!("Failed to open {}", filename);
+    let src = fs::read_to_string(filename).expect(&err);
+    let src = src.trim_end_matches('\n');
+    match Prog::parse_mode(src, mode, engine.charmap()) {
+        Ok(prog) => {
+            engine.make_process(Some(filename.to_string()), prog);
+        },
+        Err(e) => {
+            match e.position() {
+                Some((line, col)) =>
+                    eprintln!("{}:{}:{}: error: {}", filename, line, col, e),
+                None => eprintln!("{}: error: {}", filename, e),
+            }
+            std::process::exit(1);
+        }
+    }
+}
+
+fn describe(ev: &EventLog, cm: &CharMap) -> (u64, String) {
+    match ev {
+        EventLog::NewProcess(pid) => (*pid, "new process".to_string()),
+        EventLog::PrintChar(pid, c) => (*pid, format!("print {}", cm[*c])),
+        EventLog::PrintNum(pid, c) => (*pid, format!("print {:X}", c)),
+        EventLog::Play(pid, n) =>
+            (*pid, format!("play cha {} pch {} vel {} dur {}",
+                           n.cha, n.pch, n.vel, n.dur)),
+        EventLog::Finished(pid) => (*pid, "finished".to_string()),
+        EventLog::Crashed(pid, r) => (*pid, format!("crashed: {:?}", r)),
+        EventLog::Killed(pid) => (*pid, "killed".to_string()),
+    }
+}
+
+fn main() {
+    let args = read_args();
+    let config = args.config.as_ref().map(|c| FungedConfig::read_offline(c));
+
+    let period = config.as_ref().map(|c| c.period).unwrap_or(args.period);
+    let mut engine = Engine::new(period);
+    if let Some(conf) = &config {
+        engine.set_charmap(conf.charmap.clone());
+        for ch in &conf.outbound {
+            engine.add_outbound(*ch);
+        }
+        for filename in &conf.preload {
+            load(&mut engine, filename, SourceMode::Raw);
+        }
+    }
+    for filename in &args.files {
+        load(&mut engine, filename, args.mode);
+    }
+
+    let sink = RecordingSink::new();
+    let channels = config.as_ref().map(|c| &c.channels[..]).unwrap_or(&[]);
+    let smf = args.output.as_ref().map(|_| SmfSink::new(channels, period));
+    let mut bridge = if args.midi || smf.is_some() {
+        Some(MidiBridge::from_channels(channels, &sink))
+    } else {
+        None
+    };
+
+    let mut crashed = false;
+    for _ in 0..args.beats {
+        let (beat, log) = engine.step();
+        for ev in &log {
+            if let EventLog::Crashed(_, _) = ev {
+                crashed = true;
+            }
+            let (pid, desc) = describe(ev, engine.charmap());
+            let name = engine.process_name(pid);
+            if args.json {
+                println!("{}", json!({ "beat": beat, "name": name,
+                                       "event": ev }));
+            } else {
+                println!("{:6} {:4X} {:16} {}", beat, pid,
+                         name.unwrap_or_default(), desc);
+            }
+        }
+
+        if let Some(bridge) = &mut bridge {
+            bridge.step(beat, &log);
+            for (beat, msg) in sink.take() {
+                if let Some(smf) = &smf {
+                    smf.send_midi(beat, msg);
+                }
+                if !args.midi {
+                    continue;
+                }
+                if args.json {
+                    println!("{}", json!({ "beat": beat, "midi": msg }));
+                } else {
+                    println!("{:6} midi {:?}", beat, msg);
+                }
+            }
+        }
+    }
+
+    if let (Some(smf), Some(path)) = (&smf, &args.output) {
+        let tempo = args.tempo.or(config.as_ref().and_then(|c| c.tempo))
+                              .unwrap_or(120.0);
+        let err = format!("Failed to write {}", path);
+        let mut file = fs::File::create(path).expect(&err);
+        smf.write(&mut file, tempo, args.beats * period).expect(&err);
+    }
+
+    std::process::exit(if crashed { 1 } else { 0 });
+}
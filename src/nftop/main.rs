@@ -17,14 +17,15 @@
 
 use clap::{Arg, App};
 use noisefunge::api::*;
+use noisefunge::befunge::{ProcessState, Syscall};
 use pancurses::{initscr, cbreak, noecho, endwin, Input, has_colors,
-                start_color, init_pair, curs_set};
-use std::cmp::{Ordering};
+                start_color, init_pair, curs_set, Window};
+use std::cmp::{self, Ordering};
 use std::mem;
 use std::time::Duration;
 use std::collections::BinaryHeap;
 
-fn read_args() -> String {
+fn read_args() -> Endpoint {
     let matches = App::new("nftop")
                           .arg(Arg::with_name("HOST")
                                .help("Noisefunge server host")
@@ -36,10 +37,17 @@ fn read_args() -> String {
                                .required(false)
                                .env("NOISEFUNGE_PORT")
                                .default_value("1312"))
+                          .arg(Arg::with_name("SOCKET")
+                               .long("socket")
+                               .help("Noisefunge server Unix socket")
+                               .takes_value(true)
+                               .required(false)
+                               .env("NOISEFUNGE_SOCKET"))
                           .get_matches();
 
-    format!("http://{}:{}/", matches.value_of("HOST").unwrap(),
-                             matches.value_of("PORT").unwrap())
+    Endpoint::new(matches.value_of("HOST").unwrap(),
+                  matches.value_of("PORT").unwrap(),
+                  matches.value_of("SOCKET"))
 }
 
 #[derive(Eq, PartialEq, Ord, PartialOrd)]
@@ -66,9 +74,71 @@ impl Ord for OrderedProcess {
     }
 }
 
+fn describe_state(state: &ProcessState) -> String {
+    match state {
+        ProcessState::Running(false) => "Running".to_string(),
+        ProcessState::Running(true) => "Running (quote mode)".to_string(),
+        ProcessState::Trap(Syscall::Send(ch, c)) =>
+            format!("Blocked sending {:X} to channel {:X}", c, ch),
+        ProcessState::Trap(Syscall::Receive(ch)) =>
+            format!("Blocked receiving from channel {:X}", ch),
+        ProcessState::Trap(Syscall::Sleep(n)) =>
+            format!("Sleeping for {} subbeats", n),
+        ProcessState::Trap(sys) => format!("Trap: {:?}", sys),
+        st => format!("{:?}", st),
+    }
+}
+
+// Name of the op under the program counter, using the server's charmap.
+fn op_at<'a>(info: &'a ServerInfo, frame: &FrameState) -> Option<&'a str> {
+    let c = frame.prog.1.chars().nth(frame.pc)?;
+    let opcode = info.charmap.chars().position(|m| m == c)?;
+    info.ops.iter().find(|op| op.opcode as usize == opcode)
+                   .map(|op| op.name.as_str())
+}
+
+fn draw_detail(window: &Window, mut y: i32, maxy: i32, width: i32,
+               detail: &ProcDetail, info: Option<&ServerInfo>) {
+    window.color_set(2);
+    window.mvaddnstr(y, 0, format!("{:<w$}", format!("PID {:X}", detail.pid),
+                                   w = width as usize), width);
+    window.color_set(0);
+    y += 1;
+
+    let name = detail.name.as_ref().map(|n| n.as_str()).unwrap_or("");
+    let note = &detail.note;
+    let lines = vec![
+        format!("Name:    {}", name),
+        format!("Spawned: {}", detail.spawned),
+        format!("State:   {}", describe_state(&detail.state)),
+        format!("Note:    cha {:X} pch {:X} vel {:X} dur {:X}",
+                note.cha, note.pch, note.vel, note.dur),
+        format!("Stack:   {}", detail.data_stack.iter().rev()
+                                     .map(|c| format!("{:02X}", c))
+                                     .collect::<Vec<String>>()
+                                     .join(" ")),
+    ];
+    for line in lines {
+        if y >= maxy { return }
+        window.mvaddnstr(y, 0, line, width);
+        y += 1;
+    }
+
+    for (i, frame) in detail.call_stack.iter().enumerate().rev() {
+        if y >= maxy { return }
+        let (w, _) = frame.prog;
+        let op = info.and_then(|info| op_at(info, frame)).unwrap_or("");
+        window.mvaddnstr(y, 0, format!("Call {:<3} ({}, {}) {:?} {}", i,
+                                       frame.pc % w, frame.pc / w, frame.dir,
+                                       op),
+                         width);
+        y += 1;
+    }
+}
+
 fn main() {
 
-    let baseuri = read_args();
+    let endpoint = read_args();
 
     let window = initscr();
     cbreak();
@@ -81,12 +151,19 @@ fn main() {
     }
 
     window.nodelay(true);
-    let client = FungeClient::new(&baseuri);
+    window.keypad(true);
+    let client = FungeClient::new(&endpoint);
+    let detail_client = ApiClient::new(&endpoint, "nftop");
+    let info = get_info(&detail_client).ok();
     let sleep_dur = Duration::from_millis(10);
     let mut state : Option<EngineState> = None;
     let mut err = None;
     let mut needs_redraw = true;
     let mut ordering = OrderBy::DataStack;
+    let mut selected: usize = 0;
+    let mut selected_pid: Option<u64> = None;
+    let mut show_detail = false;
+    let mut detail: Option<ProcDetail> = None;
 
     'outer: loop {
 
@@ -95,6 +172,7 @@ fn main() {
             let (mut y, minx) = window.get_beg_yx();
             let (maxy, maxx) = window.get_max_yx();
             let width = maxx - minx;
+            let list_maxy = if show_detail { (maxy + y) / 2 } else { maxy };
             window.clear();
             if err.is_some() {
                 let errs = mem::take(&mut err).unwrap();
@@ -122,8 +200,10 @@ fn main() {
                                         st.beat, active, sleeping,
                                         rcount, wcount));
                 y += 1;
+                window.mvaddstr(y, 0, format!("{}", st.clock));
+                y += 1;
                 window.color_set(2);
-                window.mvaddstr(y, 0, "PID        NAME                 DATA    CALL    ");
+                window.mvaddstr(y, 0, " PID       NAME                 DATA    CALL    ");
                 window.color_set(0);
 
                 y += 1;
@@ -138,7 +218,13 @@ fn main() {
                     });
                 }
 
-                while y < maxy {
+                let rows = cmp::max(list_maxy - y, 1) as usize;
+                selected = cmp::min(selected,
+                                    cmp::min(heap.len(), rows)
+                                        .saturating_sub(1));
+                selected_pid = None;
+                let mut row = 0;
+                while y < list_maxy {
                     let pid = match heap.pop() {
                         None => break,
                         Some(op) => op.pid,
@@ -147,7 +233,12 @@ fn main() {
                     let name = st.names.get(proc.name).unwrap();
 
                     window.color_set(0);
-                    window.mvaddstr(y, 0, format!("{:X}", pid));
+                    if row == selected {
+                        selected_pid = Some(pid);
+                        window.mvaddstr(y, 0, ">");
+                    }
+                    row += 1;
+                    window.mvaddstr(y, 1, format!("{:X}", pid));
                     window.mvaddnstr(y, 11, format!("{}", name), 20);
                     if proc.data_stack > 32 { window.color_set(1); }
                     window.mvaddstr(y, 32, format!("{}", proc.data_stack));
@@ -160,6 +251,14 @@ fn main() {
                     y += 1;
                 }
 
+                if show_detail {
+                    match detail.as_ref() {
+                        Some(d) if Some(d.pid) == selected_pid =>
+                            draw_detail(&window, list_maxy, maxy, width, d,
+                                        info.as_ref()),
+                        _ => {},
+                    }
+                }
             }
             window.refresh();
         }
@@ -181,6 +280,22 @@ fn main() {
                     needs_redraw = true;
                     continue 'outer;
                 }
+                Some(Input::KeyUp) | Some(Input::Character('k')) => {
+                    selected = selected.saturating_sub(1);
+                    needs_redraw = true;
+                    continue 'outer;
+                }
+                Some(Input::KeyDown) | Some(Input::Character('j')) => {
+                    selected += 1;
+                    needs_redraw = true;
+                    continue 'outer;
+                }
+                Some(Input::Character('i')) | Some(Input::Character('I')) |
+                Some(Input::Character('\n')) => {
+                    show_detail = !show_detail;
+                    needs_redraw = true;
+                    continue 'outer;
+                }
                 Some(Input::Character('q')) | Some(Input::Character('Q')) => {
                     break 'outer;
                 },
@@ -194,6 +309,15 @@ fn main() {
                 state = Some(st);
                 err = None;
                 needs_redraw = true;
+                if show_detail {
+                    detail = match selected_pid {
+                        None => None,
+                        Some(pid) => match get_process(&detail_client, pid) {
+                            Ok(d) => Some(d),
+                            Err(e) => { err = Some(e); None },
+                        }
+                    };
+                }
             },
             Some(Err(s)) => {
                 err = Some(s);
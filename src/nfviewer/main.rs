@@ -24,7 +24,7 @@ use std::collections::{HashSet};
 use std::rc::Rc;
 use std::time::Duration;
 
-fn read_args() -> String {
+fn read_args() -> Endpoint {
     let matches = App::new("nfviewer")
                           .arg(Arg::with_name("HOST")
                                .help("Noisefunge server host")
@@ -36,10 +36,17 @@ fn read_args() -> String {
                                .required(false)
                                .env("NOISEFUNGE_PORT")
                                .default_value("1312"))
+                          .arg(Arg::with_name("SOCKET")
+                               .long("socket")
+                               .help("Noisefunge server Unix socket")
+                               .takes_value(true)
+                               .required(false)
+                               .env("NOISEFUNGE_SOCKET"))
                           .get_matches();
 
-    format!("http://{}:{}/", matches.value_of("HOST").unwrap(),
-                             matches.value_of("PORT").unwrap())
+    Endpoint::new(matches.value_of("HOST").unwrap(),
+                  matches.value_of("PORT").unwrap(),
+                  matches.value_of("SOCKET"))
 }
 
 struct View {
@@ -364,10 +371,11 @@ impl Tiler {
         let (miny, minx) = win.get_beg_yx();
         let (maxy, maxx) = win.get_max_yx();
 
-        // Clear and print beat.
+        // Clear and print beat and clock.
         win.clear();
         win.color_set(0);
-        win.mvaddstr(maxy - 1, 0, format!("{}", self.state.beat));
+        win.mvaddstr(maxy - 1, 0, format!("{}  {}", self.state.beat,
+                                          self.state.clock));
 
         // Error bar
         let elen = self.errors.len();
@@ -455,7 +463,7 @@ impl Tiler {
 
 fn main() {
 
-    let baseuri = read_args();
+    let endpoint = read_args();
 
     let window = initscr();
     cbreak();
@@ -473,7 +481,7 @@ fn main() {
     }
     window.nodelay(true);
 
-    let client = FungeClient::new(&baseuri);
+    let client = FungeClient::new(&endpoint);
     let sleep_dur = Duration::from_millis(10);
     let mut tiler = Tiler::new();
 
@@ -89,7 +89,7 @@ pub struct Vertex {
     color: VertexRGBA,
 }
 
-fn read_args() -> String {
+fn read_args() -> Endpoint {
     let matches = App::new("nfviewer")
                           .arg(Arg::with_name("HOST")
                                .help("Noisefunge server host")
@@ -101,10 +101,17 @@ fn read_args() -> String {
                                .required(false)
                                .env("NOISEFUNGE_PORT")
                                .default_value("1312"))
+                          .arg(Arg::with_name("SOCKET")
+                               .long("socket")
+                               .help("Noisefunge server Unix socket")
+                               .takes_value(true)
+                               .required(false)
+                               .env("NOISEFUNGE_SOCKET"))
                           .get_matches();
 
-    format!("http://{}:{}/", matches.value_of("HOST").unwrap(),
-                             matches.value_of("PORT").unwrap())
+    Endpoint::new(matches.value_of("HOST").unwrap(),
+                  matches.value_of("PORT").unwrap(),
+                  matches.value_of("SOCKET"))
 }
 
 #[derive(Copy, Clone, Debug, Hash)]
@@ -939,9 +946,9 @@ fn icons() -> Vec<PixelImage> {
 
 fn main() {
 
-    let baseuri = read_args();
+    let endpoint = read_args();
 
-    let client = FungeClient::new(&baseuri);
+    let client = FungeClient::new(&endpoint);
     let sleep_dur = Duration::from_millis(5);
 
     let mut width = 640;
@@ -961,6 +968,20 @@ fn main() {
     let font = ab_glyph::FontArc::try_from_slice(
                     include_bytes!("DejaVuSansMono.ttf")
                 ).expect("Failed to load font.");
+    // Programs arrive drawn in the server's charmap, so point out any of its
+    // glyphs the bundled font can't show.
+    match get_info(&ApiClient::new(&endpoint, "nfviewergl")) {
+        Ok(info) => {
+            let missing : String = info.charmap.chars()
+                .filter(|c| font.glyph_id(*c).0 == 0)
+                .collect();
+            if !missing.is_empty() {
+                errbar.push_err(&format!("font lacks: {}", missing));
+            }
+        },
+        Err(e) => errbar.push_err(&format!("info_err: {}", e)),
+    }
+
     let mut glyph_brush = GlyphBrushBuilder::using_font(font.clone())
                                             .build(&mut surface);
 
@@ -975,6 +996,7 @@ fn main() {
     surface.window.set_icon_from_pixels(icons());
 
     let mut beat = 0;
+    let mut clock = ClockState::default();
 
     let mut animator = Animator::new();
 
@@ -1062,7 +1084,7 @@ fn main() {
         tess_queue.push(errbar.make_bar(&mut surface));
         let back_buffer = surface.back_buffer().unwrap();
 
-        let beat_str = format!("{}", beat);
+        let beat_str = format!("{}  {}", beat, clock);
         glyph_brush.queue(Section::default()
             .with_screen_position((0.,height as f32 - 20.))
             .add_text(
@@ -1130,6 +1152,7 @@ fn main() {
                     errbar.push_err(&format!("{:X}: {:?}", pid, msg));
                 }
                 beat = st.beat;
+                clock = st.clock.clone();
                 animator.update(st, &screen);
                 if fps {
                     print!("{} FPS\n",frames as f32 / dur);